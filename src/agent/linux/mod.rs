@@ -1,24 +1,40 @@
 use super::{
-    Agent, AgentDevice, AsyncStream, Device, DeviceType, Executor, Filter, Packet, Stream,
+    Agent, AgentDevice, AsyncStream, Device, DeviceType, Executor, Filter, Packet, RadioInfo,
+    Stream,
 };
 use crate::connection::Connection;
+use crate::metrics;
 use crate::utils::timeout::{TimeoutExt, DEFAULT_TIMEOUT};
-use airnetwork::{AirNetwork, TxPacket};
+use airnetwork::{AirNetwork, RxInfo, TxInfo, TxPacket};
 use anyhow::{anyhow, Context as _, Result};
 use futures::{pin_mut, ready};
 use regex::Regex;
 use std::{
+    convert::TryFrom,
     future::Future,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::{
     io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::oneshot,
     time::{sleep, timeout, Duration},
 };
 
 mod airnetwork;
 
+fn radio_info(rx_info: &RxInfo) -> RadioInfo {
+    let clamp = |v: i32| v.clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+    RadioInfo {
+        tsft: rx_info.machine,
+        signal: clamp(rx_info.power),
+        noise: clamp(rx_info.noise),
+        freq: u16::try_from(rx_info.freq).unwrap_or(0),
+        rate: rx_info.rate,
+        antenna: u8::try_from(rx_info.antenna).unwrap_or(0),
+    }
+}
+
 pub struct LinuxAgentDevice<S> {
     c: AirNetwork<S>,
     name: String,
@@ -53,10 +69,12 @@ where
             };
             let pkt = Packet {
                 channel: p.rx_info.channel,
+                radio: Some(radio_info(&p.rx_info)),
                 data: p.data,
             };
             if let Some(true) = self.filter.as_ref().map(|f| f(&pkt)) {
                 // drop
+                metrics::DEVICE_FILTERED.inc();
                 continue;
             }
             return Poll::Ready(Some(Ok(pkt)));
@@ -86,11 +104,15 @@ where
         let written = self
             .c
             .write(TxPacket {
+                // 0 leaves the rate to the driver
+                tx_info: TxInfo {
+                    rate: packet.radio.map_or(0, |r| r.rate),
+                },
                 data: packet.data,
-                ..Default::default()
             })
             .await?;
         if written != pkt_len {
+            metrics::DEVICE_SHORT_WRITES.inc();
             log::warn!(
                 "send it not successed, sent: {}, packet: {}",
                 written,
@@ -112,6 +134,8 @@ where
 pub struct LinuxAgent<F> {
     conn: LinuxExecutor,
     factory: F,
+    /// pid of the airserv-ng started by `get_device`
+    airserv_pid: Option<u32>,
 }
 
 impl<F> LinuxAgent<F> {
@@ -123,6 +147,7 @@ impl<F> LinuxAgent<F> {
         Ok(LinuxAgent {
             conn: LinuxExecutor::from_factory(&factory).await?,
             factory,
+            airserv_pid: None,
         })
     }
 
@@ -221,10 +246,18 @@ where
 
         let serv = LinuxExecutor::from_factory(&self.factory).await?;
 
+        let (pid_tx, pid_rx) = oneshot::channel();
         tokio::spawn(async move {
-            let cmd = format!("airserv-ng -p 16666 -d {} -v 1 2>&1", device_name);
+            // the shell prints its pid, which airserv-ng keeps
+            let cmd = format!(
+                "sh -c 'echo $$; exec airserv-ng -p 16666 -d {} -v 1 2>&1'",
+                device_name
+            );
             let serv_stream = serv.exec_stream(cmd.as_bytes()).await?;
             let mut s = BufReader::new(serv_stream);
+            let mut pid = String::new();
+            s.read_line(&mut pid).await?;
+            let _ = pid_tx.send(pid.trim().parse::<u32>());
             loop {
                 let mut line = String::new();
                 s.read_line(&mut line).await?;
@@ -236,6 +269,11 @@ where
             log::error!("airserv-ng exited");
             Ok::<(), anyhow::Error>(())
         });
+        let pid = timeout(DEFAULT_TIMEOUT, pid_rx)
+            .await
+            .context("airserv-ng didn't start")??
+            .context("Failed to get the pid of airserv-ng")?;
+        self.airserv_pid = Some(pid);
 
         sleep(Duration::from_millis(500)).await;
 
@@ -244,6 +282,14 @@ where
 
         Ok(Box::new(LinuxAgentDevice::new(device.name.clone(), stream)))
     }
+
+    async fn cleanup(&mut self) -> Result<()> {
+        log::debug!("cleanup");
+        if let Some(pid) = self.airserv_pid.take() {
+            self.conn.exec(&format!("kill {}", pid)).await?;
+        }
+        Ok(())
+    }
 }
 
 pub struct LinuxExecutor(Connection);
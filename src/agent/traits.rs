@@ -1,16 +1,37 @@
 use super::Platform;
 pub use crate::connection::AsyncStream;
 use anyhow::Result;
+use deku::prelude::*;
 pub use futures::Stream;
 pub use tokio::{
     io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite},
     time::{timeout, Duration},
 };
 
+/// What the radio reported about a received frame, relayed with it
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone, Copy, Default)]
+pub struct RadioInfo {
+    /// MAC timestamp in microseconds
+    pub tsft: u64,
+    /// dBm, 0 if the driver doesn't report it
+    pub signal: i8,
+    pub noise: i8,
+    /// MHz
+    pub freq: u16,
+    /// bit/s
+    pub rate: u32,
+    pub antenna: u8,
+}
+
+/// Length of `RadioInfo` on the wire
+pub const RADIO_INFO_LEN: usize = 8 + 1 + 1 + 2 + 4 + 1;
+
 #[derive(Debug)]
 pub struct Packet {
     pub channel: u32,
     pub data: Vec<u8>,
+    /// set on received frames, and on frames injected with a given rate
+    pub radio: Option<RadioInfo>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -67,6 +88,8 @@ pub trait Agent {
     // async fn capture_packets(&mut self, device: &Device) -> Result<Box<dyn Stream<Item=Result<Packet>> + Unpin + Send + 'static>>;
     // async fn send_packets<'a>(&mut self, device: &Device, packets: &'a (dyn Stream<Item=Packet> + Unpin + Send + Sync)) -> Result<()>;
     async fn get_device(&mut self, device: &Device) -> Result<BoxAgentDevice>;
+    /// Stops the helper processes started by `get_device`
+    async fn cleanup(&mut self) -> Result<()>;
     fn platform(&self) -> Platform;
 }
 
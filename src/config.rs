@@ -1,25 +1,62 @@
 use crate::agent::AgentConfig;
-use serde_derive::Deserialize;
+use crate::wlan_play::fragment::DEFAULT_MTU;
+use crate::wlan_play::transport::ServerAddr;
+use anyhow::Result;
+use deku::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, DekuRead, DekuWrite, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[deku(type = "u8")]
 pub enum Mode {
+    #[deku(id = "0")]
     Host,
+    #[deku(id = "1")]
     Station,
+    /// receives the frames of the room without injecting any, needs no device
+    #[deku(id = "2")]
+    Observer,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    /// agent config
-    pub agent: AgentConfig,
-    /// device must be monitor type
-    pub device: String,
-    /// host or station mode
+    /// agent config, not needed by observers
+    pub agent: Option<AgentConfig>,
+    /// device must be monitor type, not needed by observers
+    pub device: Option<String>,
+    /// host, station or observer mode
     pub mode: Mode,
-    /// server address:port
-    pub server: SocketAddr,
+    /// server address:port, or `tcp://address:port` and `ws://address:port/path`
+    /// where UDP is blocked. Optional with an embedded `relay`.
+    pub server: Option<ServerAddr>,
+    /// room on the server, frames are only relayed within the same room
+    #[serde(default)]
+    pub room: String,
+    /// pre-shared key of the server, must match the server's `--key`
+    pub key: Option<String>,
+    /// end-to-end encryption passphrase, shared by all peers in the room
+    pub passphrase: Option<String>,
+    /// batch the frames sent to the server for up to this many microseconds,
+    /// 0 sends each frame right away
+    #[serde(default)]
+    pub batch_us: u64,
+    /// largest datagram to send, larger frames are fragmented
+    #[serde(default = "default_mtu")]
+    pub mtu: usize,
+    /// compress data frames to and from the server, if it supports it. no
+    /// effect with a `passphrase`, encrypted frames don't get smaller
+    #[serde(default)]
+    pub compress: bool,
+    /// run a relay in the client too, takes the same keys as the server
+    /// config file except `log`. Peers join it instead of `server`.
+    pub relay: Option<ServerConfig>,
+}
+
+fn default_mtu() -> usize {
+    DEFAULT_MTU
 }
 
 #[derive(Debug, StructOpt)]
@@ -29,15 +66,279 @@ pub struct ClientOpt {
     #[structopt(short, long, default_value = "config.toml", parse(from_os_str))]
     pub cfg: PathBuf,
 
-    /// Write packets to pcap file
+    /// Write packets to pcapng file
     #[structopt(short, long, parse(from_os_str))]
     pub pcap: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[structopt(long)]
+    pub metrics: Option<SocketAddr>,
+
+    /// Also relay for peers on this UDP address, e.g. 0.0.0.0:19198, replaces
+    /// `udp` of the `relay` config
+    #[structopt(long)]
+    pub relay: Option<SocketAddr>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<ClientCmd>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ClientCmd {
+    /// List the Switch sessions hosted in the room, then exit
+    Sessions {
+        /// List the sessions of all rooms
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Print the RTT and loss statistics of a running client, then exit
+    Status {
+        /// `--metrics` address of the client
+        #[structopt(long, default_value = "127.0.0.1:9100")]
+        addr: SocketAddr,
+    },
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "A server for wlan_play")]
 pub struct ServerOpt {
-    /// Listening port
-    #[structopt(short, long, default_value = "19198")]
-    pub port: u16,
+    /// Config file, see server_example.toml
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Listening UDP port on 0.0.0.0, replaces `udp` of the config file
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+
+    /// Seconds without any frame before a peer is dropped [default: 30]
+    #[structopt(long)]
+    pub peer_timeout: Option<u64>,
+
+    /// Also accept length-prefixed TCP connections on this address, e.g. 0.0.0.0:19198
+    #[structopt(long)]
+    pub tcp: Option<SocketAddr>,
+
+    /// Also accept WebSocket connections on this address, e.g. 0.0.0.0:19199
+    #[structopt(long)]
+    pub ws: Option<SocketAddr>,
+
+    /// Pre-shared key, datagrams not authenticated with it are dropped
+    #[structopt(long)]
+    pub key: Option<String>,
+
+    /// Serve the status and admin API on this address, e.g. 127.0.0.1:8080
+    #[structopt(long)]
+    pub http: Option<SocketAddr>,
+
+    /// Bearer token required by the HTTP API, admin actions are only
+    /// accepted from localhost without it
+    #[structopt(long)]
+    pub http_token: Option<String>,
+
+    /// Record relayed frames of each room to a pcapng file in this directory
+    #[structopt(long, parse(from_os_str))]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// hosts to stations and stations to hosts
+    Roles,
+    /// every peer to every other peer of the room
+    Flood,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiHost {
+    /// hosts don't get each other's frames
+    Isolate,
+    /// hosts get each other's frames, e.g. when each site hosts its own Switch
+    Share,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    pub routing: Routing,
+    /// with `Roles`, what hosts of the same room get from each other
+    pub multi_host: MultiHost,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        RoutingConfig {
+            routing: Routing::Roles,
+            multi_host: MultiHost::Isolate,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    /// peers allowed in the room at once
+    pub max_peers: Option<usize>,
+    /// overrides the routing of the server
+    pub routing: Option<RoutingConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// peers allowed on the server at once
+    pub max_peers: Option<usize>,
+    /// peers allowed in rooms without their own `max_peers`
+    pub max_peers_per_room: Option<usize>,
+    /// frames a peer may send per second, with a burst of one second
+    pub packets_per_sec: Option<u32>,
+    /// bytes a peer may send per second, with a burst of one second
+    pub bytes_per_sec: Option<u32>,
+    /// larger datagrams are dropped and count as bad frames
+    pub max_datagram_size: usize,
+    /// bad frames an IP may send within a minute before it is banned, 0 to never ban
+    pub max_bad_frames: u32,
+    /// seconds an IP is banned for after too many bad frames
+    pub bad_frames_ban: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_peers: None,
+            max_peers_per_room: None,
+            packets_per_sec: None,
+            bytes_per_sec: None,
+            max_datagram_size: 2048,
+            max_bad_frames: 20,
+            bad_frames_ban: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// UDP addresses to listen on, e.g. "0.0.0.0:19198" or "[::]:19198"
+    pub udp: Vec<SocketAddr>,
+    /// addresses to accept length-prefixed TCP connections on
+    pub tcp: Vec<SocketAddr>,
+    /// addresses to accept WebSocket connections on
+    pub ws: Vec<SocketAddr>,
+    /// seconds without any frame before a peer is dropped
+    pub peer_timeout: u64,
+    /// pre-shared keys, datagrams authenticated with any of them are accepted
+    pub keys: Vec<String>,
+    /// log filter, `RUST_LOG` takes precedence
+    pub log: String,
+    /// address of the status and admin API
+    pub http: Option<SocketAddr>,
+    /// bearer token required by the HTTP API
+    pub http_token: Option<String>,
+    /// copies of a frame relayed by other peers of the room within this many
+    /// milliseconds are dropped, 0 to relay them all
+    pub dedup_window_ms: u64,
+    /// directory to record relayed frames of each room to, as pcapng files
+    pub record: Option<PathBuf>,
+    /// largest datagram to send, larger frames are fragmented
+    pub mtu: usize,
+    /// where frames go, observers always get all of them
+    pub routing: RoutingConfig,
+    /// only rooms defined in `rooms` can be joined
+    pub restrict_rooms: bool,
+    pub rooms: HashMap<String, RoomConfig>,
+    pub limits: LimitsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            udp: vec![SocketAddr::from(([0, 0, 0, 0], 19198))],
+            tcp: vec![],
+            ws: vec![],
+            peer_timeout: 30,
+            keys: vec![],
+            log: "wlan_play=info".to_string(),
+            http: None,
+            http_token: None,
+            dedup_window_ms: 100,
+            record: None,
+            mtu: DEFAULT_MTU,
+            routing: RoutingConfig::default(),
+            restrict_rooms: false,
+            rooms: HashMap::new(),
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file if any, then applies the command line on top of it
+    pub fn load(opt: ServerOpt) -> Result<ServerConfig> {
+        let mut config: ServerConfig = match &opt.config {
+            Some(path) => toml::from_slice(&std::fs::read(path)?)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(port) = opt.port {
+            config.udp = vec![SocketAddr::from(([0, 0, 0, 0], port))];
+        }
+        if let Some(peer_timeout) = opt.peer_timeout {
+            config.peer_timeout = peer_timeout;
+        }
+        if let Some(tcp) = opt.tcp {
+            config.tcp = vec![tcp];
+        }
+        if let Some(ws) = opt.ws {
+            config.ws = vec![ws];
+        }
+        if let Some(key) = opt.key {
+            config.keys = vec![key];
+        }
+        if opt.http.is_some() {
+            config.http = opt.http;
+        }
+        if opt.http_token.is_some() {
+            config.http_token = opt.http_token;
+        }
+        if opt.record.is_some() {
+            config.record = opt.record;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            udp = ["0.0.0.0:19198", "[::]:19199"]
+            keys = ["old", "new"]
+            restrict_rooms = true
+            [routing]
+            multi_host = "Share"
+            [rooms.lobby]
+            max_peers = 8
+            [rooms.lobby.routing]
+            routing = "Flood"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.udp.len(), 2);
+        assert!(config.udp[1].is_ipv6());
+        assert_eq!(config.peer_timeout, 30);
+        assert_eq!(config.rooms["lobby"].max_peers, Some(8));
+        assert_eq!(config.routing.routing, Routing::Roles);
+        assert_eq!(config.routing.multi_host, MultiHost::Share);
+        let lobby = config.rooms["lobby"].routing.unwrap();
+        assert_eq!(lobby.routing, Routing::Flood);
+        assert_eq!(config.limits.max_peers, None);
+        assert_eq!(config.limits.max_bad_frames, 20);
+
+        // typos are errors, in nested tables too
+        assert!(toml::from_str::<ServerConfig>("peer_timout = 5").is_err());
+        assert!(toml::from_str::<ServerConfig>("[limits]\nmax_peer = 5").is_err());
+    }
 }
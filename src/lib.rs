@@ -4,5 +4,6 @@ pub use wlan_play::{client, server};
 pub mod agent;
 pub mod config;
 pub mod connection;
+pub mod metrics;
 pub mod utils;
 pub mod wlan_play;
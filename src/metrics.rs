@@ -0,0 +1,318 @@
+use crate::wlan_play::seq::{Seq, SeqTracker};
+use anyhow::Result;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+/// What counts an event, the client and the relay embedded in it share the
+/// metrics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    Client,
+    Relay,
+}
+
+impl Component {
+    /// Value of the `component` label
+    pub fn label(self) -> &'static str {
+        match self {
+            Component::Client => "client",
+            Component::Relay => "relay",
+        }
+    }
+}
+
+/// Relay frames, labeled by direction `rx` or `tx`
+pub static FRAMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_frames_total",
+        "Relay frames received or sent",
+        &["direction", "component"]
+    )
+    .unwrap()
+});
+/// Relay bytes, labeled by direction `rx` or `tx`
+pub static BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_bytes_total",
+        "Relay bytes received or sent",
+        &["direction", "component"]
+    )
+    .unwrap()
+});
+pub static PARSE_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_parse_errors_total",
+        "Relay datagrams failed to parse",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static AUTH_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_auth_failures_total",
+        "Relay datagrams dropped because they are not authenticated",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static RATE_LIMITED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_rate_limited_total",
+        "Relay frames dropped because the peer exceeded its rate limit"
+    )
+    .unwrap()
+});
+pub static OVERSIZED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_oversized_total",
+        "Relay datagrams dropped because they are too large"
+    )
+    .unwrap()
+});
+pub static UNICAST: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_unicast_total",
+        "Data frames forwarded only to the peer behind their receiver address"
+    )
+    .unwrap()
+});
+pub static DUPLICATES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_duplicates_total",
+        "Data frames dropped because another peer relayed them first"
+    )
+    .unwrap()
+});
+pub static FRAGMENTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_fragmented_total",
+        "Frames split into fragments because they exceed the MTU",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static REASSEMBLY_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_reassembly_timeouts_total",
+        "Fragmented frames dropped because a fragment didn't arrive in time",
+        &["component"]
+    )
+    .unwrap()
+});
+/// Data frames before compression, labeled `tx` if we compressed them or `rx`
+pub static COMPRESSION_PLAIN_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_compression_plain_bytes_total",
+        "Data frames compressed or decompressed, in bytes before compression",
+        &["direction", "component"]
+    )
+    .unwrap()
+});
+/// The same frames compressed, the ratio of both is the compression ratio
+pub static COMPRESSION_COMPRESSED_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_compression_compressed_bytes_total",
+        "Data frames compressed or decompressed, in bytes after compression",
+        &["direction", "component"]
+    )
+    .unwrap()
+});
+pub static CONTEXT_RESYNCS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_context_resyncs_total",
+        "Header contexts defined again because the receiver didn't know them",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static PEERS: Lazy<IntGauge> =
+    Lazy::new(|| register_int_gauge!("wlan_play_peers", "Peers joined to the server").unwrap());
+pub static DEVICE_FILTERED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_device_filtered_total",
+        "Captured 802.11 frames dropped by the device filter"
+    )
+    .unwrap()
+});
+pub static DEVICE_SHORT_WRITES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "wlan_play_device_short_writes_total",
+        "802.11 frames not fully injected by the device"
+    )
+    .unwrap()
+});
+pub static RELAY_RTT: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "wlan_play_relay_rtt_seconds",
+        "Round trip time to the relay server",
+        exponential_buckets(0.001, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+pub static PEER_RTT: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "wlan_play_peer_rtt_seconds",
+        "Round trip time to other peers through the relay server",
+        exponential_buckets(0.001, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+pub static SEQ_GAPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_seq_gaps_total",
+        "Data frames missing from the sequence of their sender when a later one arrived",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static SEQ_REORDERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_seq_reordered_total",
+        "Data frames arriving after a later frame of their sender",
+        &["component"]
+    )
+    .unwrap()
+});
+pub static SEQ_DUPLICATES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "wlan_play_seq_duplicates_total",
+        "Data frames dropped because their sequence number was already seen",
+        &["component"]
+    )
+    .unwrap()
+});
+
+/// Counts an event of `component`
+pub fn inc(counter: &IntCounterVec, component: Component) {
+    counter.with_label_values(&[component.label()]).inc();
+}
+
+pub fn rx(component: Component, bytes: usize) {
+    let labels = &["rx", component.label()];
+    FRAMES.with_label_values(labels).inc();
+    BYTES.with_label_values(labels).inc_by(bytes as u64);
+}
+
+pub fn tx(component: Component, bytes: usize) {
+    let labels = &["tx", component.label()];
+    FRAMES.with_label_values(labels).inc();
+    BYTES.with_label_values(labels).inc_by(bytes as u64);
+}
+
+/// Tracks the sequence number of a data frame, counting gaps, reordering and duplicates
+pub fn seq(component: Component, tracker: &mut SeqTracker, seq: u32) -> Seq {
+    let labels = &[component.label()];
+    let lost = tracker.stats.lost;
+    let seq = tracker.track(seq);
+    SEQ_GAPS
+        .with_label_values(labels)
+        .inc_by(tracker.stats.lost.saturating_sub(lost));
+    match seq {
+        Seq::Reordered => SEQ_REORDERED.with_label_values(labels).inc(),
+        Seq::Duplicate => SEQ_DUPLICATES.with_label_values(labels).inc(),
+        Seq::InOrder => {}
+    }
+    seq
+}
+
+fn init_common(component: Component) {
+    let label = component.label();
+    for direction in &["rx", "tx"] {
+        FRAMES.with_label_values(&[direction, label]);
+        BYTES.with_label_values(&[direction, label]);
+        COMPRESSION_PLAIN_BYTES.with_label_values(&[direction, label]);
+        COMPRESSION_COMPRESSED_BYTES.with_label_values(&[direction, label]);
+    }
+    for counter in &[
+        &SEQ_GAPS,
+        &SEQ_REORDERED,
+        &SEQ_DUPLICATES,
+        &FRAGMENTED,
+        &REASSEMBLY_TIMEOUTS,
+        &CONTEXT_RESYNCS,
+        &PARSE_ERRORS,
+        &AUTH_FAILURES,
+    ] {
+        counter.with_label_values(&[label]);
+    }
+}
+
+/// Registers the metrics used by the server, so they are exported before the first event
+pub fn init_server() {
+    init_common(Component::Relay);
+    Lazy::force(&RATE_LIMITED);
+    Lazy::force(&OVERSIZED);
+    Lazy::force(&UNICAST);
+    Lazy::force(&DUPLICATES);
+    Lazy::force(&PEERS);
+}
+
+/// Registers the metrics used by the client, so they are exported before the first event
+pub fn init_client() {
+    init_common(Component::Client);
+    Lazy::force(&DEVICE_FILTERED);
+    Lazy::force(&DEVICE_SHORT_WRITES);
+    Lazy::force(&RELAY_RTT);
+    Lazy::force(&PEER_RTT);
+}
+
+/// Metrics in the Prometheus text format
+pub fn response() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    match encoder.encode(&prometheus::gather(), &mut buf) {
+        Ok(_) => Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(buf.into())
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(e.to_string().into())
+            .unwrap(),
+    }
+}
+
+async fn handle<F>(req: Request<Body>, status: F) -> Result<Response<Body>, Infallible>
+where
+    F: Fn() -> serde_json::Value,
+{
+    Ok(match req.uri().path() {
+        "/metrics" => response(),
+        "/status" => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(status().to_string().into())
+            .unwrap(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+/// Serves `/metrics`, and `/status` from `status`, on `addr`
+pub async fn serve<F>(addr: SocketAddr, status: F) -> Result<()>
+where
+    F: Fn() -> serde_json::Value + Clone + Send + Sync + 'static,
+{
+    let make_svc = make_service_fn(move |_| {
+        let status = status.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let status = status.clone();
+                async move { handle(req, status).await }
+            }))
+        }
+    });
+    let http = hyper::Server::try_bind(&addr)?.serve(make_svc);
+    log::info!("Metrics listening on {}", addr);
+    http.await?;
+    Ok(())
+}
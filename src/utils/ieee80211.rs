@@ -1,9 +1,16 @@
 use deku::prelude::*;
 use std::fmt;
 
-#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Clone)]
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub struct Mac([u8; 6]);
 
+impl Mac {
+    /// Group address, including broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
 impl fmt::Debug for Mac {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let m = &self.0;
@@ -15,7 +22,7 @@ impl fmt::Debug for Mac {
     }
 }
 
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Hash, Clone)]
 #[deku(type = "u8", bits = "2")]
 pub enum FrameType {
     #[deku(id = "0b00")]
@@ -26,7 +33,7 @@ pub enum FrameType {
     Data,
 }
 
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Hash, Clone)]
 pub struct Flags {
     #[deku(bits = 1)]
     pub order: u8,
@@ -46,7 +53,7 @@ pub struct Flags {
     pub to_ds: u8,
 }
 
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Hash, Clone)]
 pub struct FrameControl {
     #[deku(bits = 4)]
     pub sub_type: u8,
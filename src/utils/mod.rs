@@ -1,4 +1,6 @@
 pub mod ieee80211;
+pub mod pcapng;
+pub mod signal;
 pub mod timeout;
 
 pub struct Packet {
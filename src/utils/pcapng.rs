@@ -0,0 +1,227 @@
+use crate::agent::RadioInfo;
+use anyhow::{anyhow, Result};
+use pcap_parser::{
+    EnhancedPacketBlock, InterfaceDescriptionBlock, Linktype, OptionCode, PcapNGOption,
+    SectionHeaderBlock, ToVec,
+};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LINKTYPE_IEEE802_11_RADIOTAP: i32 = 127;
+const SNAPLEN: u32 = 65535;
+
+/// Center frequency in MHz of a 2.4 GHz or 5 GHz channel
+pub fn channel_freq(channel: u32) -> u16 {
+    match channel {
+        14 => 2484,
+        1..=13 => 2407 + 5 * channel as u16,
+        // 0 for channels no band has
+        _ => u16::try_from(channel)
+            .ok()
+            .filter(|c| *c != 0)
+            .and_then(|c| c.checked_mul(5)?.checked_add(5000))
+            .unwrap_or(0),
+    }
+}
+
+/// Radiotap header with the flags and channel fields, and the TSFT, rate,
+/// antenna signal, noise and antenna fields if `radio` is known
+fn radiotap(channel: u32, radio: Option<&RadioInfo>) -> Vec<u8> {
+    let freq = match radio {
+        Some(r) if r.freq != 0 => r.freq,
+        _ => channel_freq(channel),
+    };
+    let band: u16 = if freq < 3000 { 0x0080 } else { 0x0100 };
+    // version, pad, length and present are filled in at the end
+    let mut h = vec![0u8; 8];
+    let mut present = (1u32 << 1) | (1 << 3);
+    if let Some(r) = radio {
+        present |= 1 << 0;
+        h.extend_from_slice(&r.tsft.to_le_bytes());
+    }
+    // flags: no FCS at the end
+    h.push(0);
+    if let Some(r) = radio {
+        // in 500 kbit/s
+        present |= 1 << 2;
+        h.push((r.rate / 500_000).min(u8::MAX as u32) as u8);
+    }
+    // the channel is 2-aligned
+    h.resize((h.len() + 1) & !1, 0);
+    h.extend_from_slice(&freq.to_le_bytes());
+    h.extend_from_slice(&band.to_le_bytes());
+    if let Some(r) = radio {
+        present |= (1 << 5) | (1 << 6) | (1 << 11);
+        h.extend_from_slice(&[r.signal as u8, r.noise as u8, r.antenna]);
+    }
+    let len = h.len() as u16;
+    h[2..4].copy_from_slice(&len.to_le_bytes());
+    h[4..8].copy_from_slice(&present.to_le_bytes());
+    h
+}
+
+fn padded(value: &[u8]) -> Vec<u8> {
+    let mut v = value.to_vec();
+    v.resize((value.len() + 3) & !3, 0);
+    v
+}
+
+fn gen_err<E: std::fmt::Debug>(e: E) -> anyhow::Error {
+    anyhow!("pcapng: {:?}", e)
+}
+
+/// Writes 802.11 frames with a radiotap header to a pcapng stream
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut w: W) -> Result<Writer<W>> {
+        let app = concat!("wlan-play ", env!("CARGO_PKG_VERSION"));
+        let app_value = padded(app.as_bytes());
+        let mut shb = SectionHeaderBlock {
+            block_type: 0,
+            block_len1: 0,
+            bom: 0,
+            major_version: 0,
+            minor_version: 0,
+            section_len: -1,
+            options: vec![
+                PcapNGOption {
+                    code: OptionCode::ShbUserAppl,
+                    len: app.len() as u16,
+                    value: &app_value,
+                },
+                PcapNGOption {
+                    code: OptionCode::EndOfOpt,
+                    len: 0,
+                    value: b"",
+                },
+            ],
+            block_len2: 0,
+        };
+        w.write_all(&shb.to_vec().map_err(gen_err)?)?;
+        let mut idb = InterfaceDescriptionBlock {
+            block_type: 0,
+            block_len1: 0,
+            linktype: Linktype(LINKTYPE_IEEE802_11_RADIOTAP),
+            reserved: 0,
+            snaplen: SNAPLEN,
+            options: vec![],
+            block_len2: 0,
+            if_tsresol: 6,
+            if_tsoffset: 0,
+        };
+        w.write_all(&idb.to_vec().map_err(gen_err)?)?;
+        Ok(Writer { w })
+    }
+    /// Writes a frame received on `channel`, with an optional comment
+    pub fn write(
+        &mut self,
+        channel: u32,
+        data: &[u8],
+        radio: Option<&RadioInfo>,
+        comment: Option<&str>,
+    ) -> Result<()> {
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        let packet = [&radiotap(channel, radio)[..], data].concat();
+        let comment_value = comment.map(|c| padded(c.as_bytes()));
+        let mut options = vec![];
+        if let (Some(comment), Some(value)) = (comment, &comment_value) {
+            options.push(PcapNGOption {
+                code: OptionCode::Comment,
+                len: comment.len() as u16,
+                value,
+            });
+            options.push(PcapNGOption {
+                code: OptionCode::EndOfOpt,
+                len: 0,
+                value: b"",
+            });
+        }
+        let mut epb = EnhancedPacketBlock {
+            block_type: 0,
+            block_len1: 0,
+            if_id: 0,
+            ts_high: (micros >> 32) as u32,
+            ts_low: micros as u32,
+            caplen: packet.len() as u32,
+            origlen: packet.len() as u32,
+            data: &packet,
+            options,
+            block_len2: 0,
+        };
+        self.w.write_all(&epb.to_vec().map_err(gen_err)?)?;
+        Ok(())
+    }
+    pub fn flush(&mut self) -> Result<()> {
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap_parser::traits::PcapReaderIterator;
+    use pcap_parser::{Block, PcapBlockOwned, PcapNGReader};
+
+    #[test]
+    fn test_channel_freq() {
+        assert_eq!(channel_freq(1), 2412);
+        assert_eq!(channel_freq(6), 2437);
+        assert_eq!(channel_freq(14), 2484);
+        assert_eq!(channel_freq(36), 5180);
+        assert_eq!(channel_freq(0), 0);
+        assert_eq!(channel_freq(20000), 0);
+        assert_eq!(channel_freq(u32::MAX), 0);
+    }
+
+    #[test]
+    fn test_write() {
+        let mut buf = vec![];
+        let mut writer = Writer::new(&mut buf).unwrap();
+        writer
+            .write(6, &[0xd0, 0, 0, 0, 1], None, Some("peer 1"))
+            .unwrap();
+        let radio = RadioInfo {
+            tsft: 5,
+            signal: -40,
+            noise: -90,
+            freq: 0,
+            rate: 1_000_000,
+            antenna: 1,
+        };
+        writer
+            .write(11, &[0xd4, 0, 0, 0], Some(&radio), None)
+            .unwrap();
+        let mut reader = PcapNGReader::new(65536, &buf[..]).unwrap();
+        let mut packets = vec![];
+        loop {
+            match reader.next() {
+                Ok((offset, block)) => {
+                    if let PcapBlockOwned::NG(Block::EnhancedPacket(epb)) = block {
+                        packets.push((epb.data[..epb.caplen as usize].to_vec(), epb.options.len()));
+                    }
+                    reader.consume(offset);
+                }
+                Err(pcap_parser::PcapError::Eof) => break,
+                Err(pcap_parser::PcapError::Incomplete) => reader.refill().unwrap(),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0].0[..14], &radiotap(6, None)[..]);
+        assert_eq!(&packets[0].0[2..8], &[14, 0, 0x0a, 0, 0, 0]);
+        assert_eq!(&packets[0].0[14..], &[0xd0, 0, 0, 0, 1]);
+        assert_eq!(packets[0].1, 2);
+        let h = &packets[1].0;
+        assert_eq!(&h[2..8], &[25, 0, 0x6f, 0x08, 0, 0]);
+        assert_eq!(&h[8..16], &5u64.to_le_bytes());
+        assert_eq!(h[17], 2);
+        assert_eq!(&h[18..20], &2462u16.to_le_bytes());
+        assert_eq!(&h[22..25], &[-40i8 as u8, -90i8 as u8, 1]);
+        assert_eq!(&h[25..], &[0xd4, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Result};
+use deku::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TAG_LEN: usize = 16;
+/// Bytes `Auth::seal` adds to a datagram
+pub const TRAILER_LEN: usize = 8 + 8 + 4 + TAG_LEN;
+/// Datagrams older or newer than this are rejected, which bounds how long
+/// replay windows have to be remembered.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(120);
+/// How long an unused replay window is kept: a datagram stamped `MAX_CLOCK_SKEW` ahead
+/// is accepted until it is `MAX_CLOCK_SKEW` behind
+const REPLAY_WINDOW_TTL: Duration = Duration::from_secs(2 * MAX_CLOCK_SKEW.as_secs());
+
+/// Appended to every datagram when a pre-shared key is set
+#[derive(DekuRead, DekuWrite, Debug, PartialEq)]
+struct Trailer {
+    /// random id of the sender, picked at startup
+    session: u64,
+    /// strictly increasing per session
+    counter: u64,
+    /// unix time in seconds
+    timestamp: u32,
+    tag: [u8; TAG_LEN],
+}
+
+/// Sliding window of the last 64 counters seen from a session
+struct ReplayWindow {
+    top: u64,
+    bitmap: u64,
+    last_used: Instant,
+}
+
+impl ReplayWindow {
+    fn new() -> ReplayWindow {
+        ReplayWindow {
+            top: 0,
+            bitmap: 0,
+            last_used: Instant::now(),
+        }
+    }
+    fn check(&self, counter: u64) -> bool {
+        if counter > self.top {
+            return true;
+        }
+        let offset = self.top - counter;
+        offset < 64 && self.bitmap & (1 << offset) == 0
+    }
+    fn update(&mut self, counter: u64) {
+        self.last_used = Instant::now();
+        if counter > self.top {
+            let shift = counter - self.top;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.top = counter;
+        } else {
+            self.bitmap |= 1 << (self.top - counter);
+        }
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Authenticates datagrams with a pre-shared key.
+///
+/// Each datagram carries a HMAC over the frame bytes, the sender session, a
+/// counter and a timestamp. Counters are checked against a per-session
+/// replay window so captured datagrams can't be injected again.
+pub struct Auth {
+    mac: HmacSha256,
+    session: u64,
+    counter: u64,
+    windows: HashMap<u64, ReplayWindow>,
+}
+
+impl Auth {
+    pub fn new(key: &str) -> Auth {
+        Auth {
+            mac: HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length"),
+            session: rand::random(),
+            counter: 0,
+            windows: HashMap::new(),
+        }
+    }
+    fn tag(&self, data: &[u8], session: u64, counter: u64, timestamp: u32) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.update(&session.to_le_bytes());
+        mac.update(&counter.to_le_bytes());
+        mac.update(&timestamp.to_le_bytes());
+        mac
+    }
+    /// Appends the authentication trailer to the frame bytes
+    pub fn seal(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>> {
+        self.counter += 1;
+        let timestamp = unix_time();
+        let tag = self
+            .tag(&data, self.session, self.counter, timestamp)
+            .finalize()
+            .into_bytes();
+        let mut trailer = Trailer {
+            session: self.session,
+            counter: self.counter,
+            timestamp,
+            tag: [0; TAG_LEN],
+        };
+        trailer.tag.copy_from_slice(&tag[..TAG_LEN]);
+        data.extend(trailer.to_bytes()?);
+        Ok(data)
+    }
+    /// Verifies the trailer and returns the frame bytes
+    pub fn open<'a>(&mut self, datagram: &'a [u8]) -> Result<&'a [u8]> {
+        if datagram.len() < TRAILER_LEN {
+            return Err(anyhow!("Datagram too short"));
+        }
+        let (data, trailer) = datagram.split_at(datagram.len() - TRAILER_LEN);
+        let (_, trailer) = Trailer::from_bytes((trailer, 0))?;
+        let skew = (unix_time() as i64 - trailer.timestamp as i64).unsigned_abs();
+        if skew > MAX_CLOCK_SKEW.as_secs() {
+            return Err(anyhow!("Timestamp is {}s off, check the clock", skew));
+        }
+        if trailer.session == self.session {
+            return Err(anyhow!("Reflected datagram"));
+        }
+        if let Some(false) = self
+            .windows
+            .get(&trailer.session)
+            .map(|w| w.check(trailer.counter))
+        {
+            return Err(anyhow!("Replayed datagram"));
+        }
+        self.tag(data, trailer.session, trailer.counter, trailer.timestamp)
+            .verify_truncated_left(&trailer.tag)
+            .map_err(|_| anyhow!("Bad MAC"))?;
+        self.windows
+            .entry(trailer.session)
+            .or_insert_with(ReplayWindow::new)
+            .update(trailer.counter);
+        Ok(data)
+    }
+    /// Forgets sessions that can't be replayed anymore
+    pub fn expire(&mut self) {
+        self.windows
+            .retain(|_, w| w.last_used.elapsed() <= REPLAY_WINDOW_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let mut alice = Auth::new("secret");
+        let mut bob = Auth::new("secret");
+        let sealed = alice.seal(vec![1, 2, 3]).unwrap();
+        assert_eq!(sealed.len(), 3 + TRAILER_LEN);
+        assert_eq!(bob.open(&sealed).unwrap(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut alice = Auth::new("secret");
+        let mut bob = Auth::new("secret");
+        let mut eve = Auth::new("guess");
+
+        let sealed = eve.seal(vec![1, 2, 3]).unwrap();
+        assert!(bob.open(&sealed).is_err());
+
+        let mut sealed = alice.seal(vec![1, 2, 3]).unwrap();
+        sealed[0] = 0;
+        assert!(bob.open(&sealed).is_err());
+
+        let sealed = alice.seal(vec![1, 2, 3]).unwrap();
+        assert!(alice.open(&sealed).is_err());
+        assert!(bob.open(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut alice = Auth::new("secret");
+        let mut bob = Auth::new("secret");
+        let first = alice.seal(vec![1]).unwrap();
+        let second = alice.seal(vec![2]).unwrap();
+
+        // reordering is fine, replaying is not
+        assert!(bob.open(&second).is_ok());
+        assert!(bob.open(&first).is_ok());
+        assert!(bob.open(&first).is_err());
+        assert!(bob.open(&second).is_err());
+
+        let old = alice.seal(vec![3]).unwrap();
+        for _ in 0..64 {
+            bob.open(&alice.seal(vec![4]).unwrap()).unwrap();
+        }
+        assert!(bob.open(&old).is_err());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut alice = Auth::new("secret");
+        let mut bob = Auth::new("secret");
+        let sealed = alice.seal(vec![1]).unwrap();
+        bob.open(&sealed).unwrap();
+        let ago = |d: Duration| Instant::now().checked_sub(d).unwrap();
+
+        // still within the skew of a datagram stamped ahead
+        bob.windows.get_mut(&alice.session).unwrap().last_used =
+            ago(MAX_CLOCK_SKEW + Duration::from_secs(10));
+        bob.expire();
+        assert!(bob.open(&sealed).is_err());
+
+        bob.windows.get_mut(&alice.session).unwrap().last_used =
+            ago(REPLAY_WINDOW_TTL + Duration::from_secs(10));
+        bob.expire();
+        assert!(bob.windows.is_empty());
+    }
+}
@@ -1,20 +1,40 @@
-use super::protocol;
-use crate::agent::{self, BoxAgentDevice, Device, DeviceType, Packet};
-use crate::config::{ClientOpt, Config, Mode};
+use super::auth::{Auth, TRAILER_LEN};
+use super::compress::{CompressionStats, Compressor};
+use super::crypto::Cipher;
+use super::direct::{Paths, PUNCH_INTERVAL};
+use super::fragment::{fragment, Reassembler};
+use super::protocol::{
+    self,
+    contexts::{Decoded, Decoder, Encoder},
+};
+use super::radio::Signal;
+use super::seq::{Seq, SeqStats, SeqTracker};
+use super::server::Relay;
+use super::transport::{ServerAddr, Transport};
+use crate::agent::{self, BoxAgent, BoxAgentDevice, Device, DeviceType, Packet, RadioInfo};
+use crate::config::{ClientCmd, ClientOpt, Config, Mode};
+use crate::metrics::{self, Component};
 use crate::utils::ieee80211::{self, Frame, FrameType, Mac};
+use crate::utils::{pcapng, signal};
 use anyhow::{anyhow, Result};
 use deku::prelude::*;
 use futures::stream::TryStreamExt;
+use serde_derive::Serialize;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fs::File,
+    io::BufWriter,
     net::SocketAddr,
     path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
 };
 use tokio::select;
-use tokio::{
-    net::UdpSocket,
-    time::{timeout, Duration},
-};
+use tokio::sync::oneshot;
+use tokio::time::{interval, sleep_until, timeout, Duration, Instant};
 
 fn parse_ieee80211(data: &[u8]) -> Result<(ieee80211::Frame, &[u8])> {
     let ((body, _), frame) = match Frame::from_bytes((data, 0)) {
@@ -28,8 +48,37 @@ fn parse_ieee80211(data: &[u8]) -> Result<(ieee80211::Frame, &[u8])> {
 }
 
 pub struct WlanPlay {
+    agent: BoxAgent,
     dev: BoxAgentDevice,
-    _pcap: Option<PathBuf>,
+}
+
+/// Frames written to the `--pcap` file, if any
+pub struct Capture(Option<pcapng::Writer<BufWriter<File>>>);
+
+impl Capture {
+    pub fn create(path: Option<PathBuf>) -> Result<Capture> {
+        let writer = match path {
+            Some(path) => {
+                log::info!("Writing frames to {}", path.display());
+                Some(pcapng::Writer::new(BufWriter::new(File::create(path)?))?)
+            }
+            None => None,
+        };
+        Ok(Capture(writer))
+    }
+    pub fn write(&mut self, channel: u32, data: &[u8], radio: Option<&RadioInfo>, comment: &str) {
+        if let Some(writer) = &mut self.0 {
+            if let Err(e) = writer.write(channel, data, radio, Some(comment)) {
+                log::error!("Failed to write pcap, stop writing: {:?}", e);
+                self.0 = None;
+            }
+        }
+    }
+    pub fn flush(&mut self) {
+        if let Some(Err(e)) = self.0.as_mut().map(|w| w.flush()) {
+            log::error!("Failed to flush pcap: {:?}", e);
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
@@ -39,14 +88,28 @@ pub struct Station {
 }
 
 impl WlanPlay {
-    pub async fn new(config: &Config, pcap: Option<PathBuf>) -> Result<Self> {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let (agent_config, name) = match (&config.agent, &config.device) {
+            (Some(agent), Some(device)) => (agent, device.clone()),
+            _ => {
+                return Err(anyhow!(
+                    "`device` and `[agent]` must be set, except for observers"
+                ))
+            }
+        };
         let d = Device {
             device_type: DeviceType::Dev,
-            name: config.device.clone(),
+            name,
         };
-        let mut agent = agent::from_config(&config.agent).await?;
+        let mut agent = agent::from_config(agent_config).await?;
         let dev = agent.get_device(&d).await?;
-        Ok(WlanPlay { dev, _pcap: pcap })
+        Ok(WlanPlay { agent, dev })
+    }
+    /// Closes the device stream and stops the helpers on the agent
+    pub async fn stop(self) -> Result<()> {
+        let WlanPlay { mut agent, dev, .. } = self;
+        drop(dev);
+        agent.cleanup().await
     }
     pub async fn find_switch(&mut self) -> Result<HashMap<Mac, Station>> {
         let list = [1u32, 6, 11];
@@ -122,29 +185,896 @@ fn packet_has_mac(frame: &ieee80211::Frame, mac: &Mac) -> bool {
     false
 }
 
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+const HANDSHAKE_RETRIES: usize = 5;
+/// Keepalive intervals between two status logs
+const STATUS_TICKS: u64 = 6;
+/// How long the embedded relay gets to say goodbye and close its recordings
+const RELAY_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Peers not heard from for this long are no longer pinged
+const LINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Frame header and count of a batch
+const BATCH_OVERHEAD: usize = 3 + 2;
+
+/// Data frames waiting to be sent in one `FrameBody::Batch`
+struct Batcher {
+    /// how long the first frame may wait, None to send frames right away
+    delay: Option<Duration>,
+    /// largest batch on the wire
+    max_len: usize,
+    frames: Vec<protocol::Frame>,
+    size: usize,
+    deadline: Option<Instant>,
+}
+
+impl Batcher {
+    fn new(batch_us: u64, max_len: usize) -> Batcher {
+        Batcher {
+            delay: Some(Duration::from_micros(batch_us)).filter(|d| !d.is_zero()),
+            max_len,
+            frames: vec![],
+            size: 0,
+            deadline: None,
+        }
+    }
+    /// Whether the frame fits in a batch at all
+    fn fits(&self, frame: &protocol::Frame) -> bool {
+        BATCH_OVERHEAD + frame.size() <= self.max_len
+    }
+    /// Adds a frame, returns the frames to send first if it doesn't fit
+    fn push(&mut self, frame: protocol::Frame) -> Option<Vec<protocol::Frame>> {
+        let full = if !self.frames.is_empty()
+            && BATCH_OVERHEAD + self.size + frame.size() > self.max_len
+        {
+            Some(self.take())
+        } else {
+            None
+        };
+        if let (None, Some(delay)) = (self.deadline, self.delay) {
+            self.deadline = Some(Instant::now() + delay);
+        }
+        self.size += frame.size();
+        self.frames.push(frame);
+        full
+    }
+    fn take(&mut self) -> Vec<protocol::Frame> {
+        self.size = 0;
+        self.deadline = None;
+        std::mem::take(&mut self.frames)
+    }
+}
+
+/// What we know of another peer of the room
+struct Link {
+    seq: SeqTracker,
+    rtt: Option<Duration>,
+    last_rx: Instant,
+    /// signal strength of the frames it captures
+    signal: Signal,
+}
+
+impl Link {
+    fn new() -> Link {
+        Link {
+            seq: SeqTracker::default(),
+            rtt: None,
+            last_rx: Instant::now(),
+            signal: Signal::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct LinkStatus {
+    peer_id: u32,
+    rtt_ms: Option<f64>,
+    seq: SeqStats,
+    signal_dbm: Option<f64>,
+}
+
+/// Served on `/status` of `--metrics`, and logged now and then
+#[derive(Serialize, Debug)]
+struct ClientStatus {
+    room: String,
+    peer_id: u32,
+    role: Mode,
+    server_rtt_ms: Option<f64>,
+    peers: Vec<LinkStatus>,
+    /// data frames we compressed, and decompressed
+    tx_compression: Option<CompressionStats>,
+    rx_compression: Option<CompressionStats>,
+}
+
+fn millis(rtt: Option<Duration>) -> Option<f64> {
+    rtt.map(|d| d.as_secs_f64() * 1000.0)
+}
+
+impl ClientStatus {
+    fn report(&self) -> String {
+        let rtt = |ms: Option<f64>| match ms {
+            Some(ms) => format!("{:.1} ms", ms),
+            None => "unknown".to_string(),
+        };
+        let mut report = format!("RTT to server {}", rtt(self.server_rtt_ms));
+        if let (Some(tx), Some(rx)) = (&self.tx_compression, &self.rx_compression) {
+            let ratio = |ratio: Option<f64>| match ratio {
+                Some(ratio) => format!("{:.2}", ratio),
+                None => "unknown".to_string(),
+            };
+            report += &format!(
+                ", compression ratio {} sent, {} received",
+                ratio(tx.ratio),
+                ratio(rx.ratio)
+            );
+        }
+        for p in &self.peers {
+            report += &format!(
+                "; peer {}: RTT {}, {} frames, {} lost, {} reordered, {} duplicates",
+                p.peer_id,
+                rtt(p.rtt_ms),
+                p.seq.received,
+                p.seq.lost,
+                p.seq.reordered,
+                p.seq.duplicates
+            );
+            if let Some(dbm) = p.signal_dbm {
+                report += &format!(", signal {:.1} dBm", dbm);
+            }
+        }
+        report
+    }
+}
+
 struct Client {
-    s: UdpSocket,
+    transport: Transport,
+    auth: Option<Mutex<Auth>>,
+    cipher: Option<Cipher>,
+    role: Mode,
+    room: String,
+    peer_id: AtomicU32,
+    /// sequence number of our next data frame
+    next_seq: AtomicU32,
+    /// timestamps of our pings count from here
+    started: Instant,
+    server_rtt: Mutex<Option<Duration>>,
+    /// other peers we heard from, by id
+    links: Mutex<HashMap<u32, Link>>,
+    /// the server takes batches
+    batch: AtomicBool,
+    /// frames of received batches, with the direct peer that sent them
+    unbatched: Mutex<VecDeque<(Option<SocketAddr>, protocol::FrameBody)>>,
+    batch_us: u64,
+    /// largest datagram sent, larger frames are fragmented
+    mtu: usize,
+    /// id of the last frame fragmented
+    next_fragment: AtomicU32,
+    /// fragments by direct peer, None for the server
+    reassembler: Mutex<Reassembler<Option<SocketAddr>>>,
+    /// set if `compress` is configured
+    compressor: Option<Mutex<Compressor>>,
+    /// the server takes compressed frames too
+    compress: AtomicBool,
+    /// the server takes `ContextData`
+    header_contexts: AtomicBool,
+    tx_contexts: Mutex<Encoder>,
+    /// by direct peer, None for the server
+    rx_contexts: Mutex<HashMap<Option<SocketAddr>, Decoder>>,
+    /// we can punch direct paths. Not with a relay in this process, which
+    /// would advertise its loopback address, nor as an observer that only
+    /// listens to the server.
+    punch: bool,
+    /// peers may be reached directly, the server supports it and we use UDP
+    direct: AtomicBool,
+    paths: Mutex<Paths>,
 }
 
 impl Client {
-    async fn connect(addr: SocketAddr) -> Result<Client> {
-        let s = UdpSocket::bind("0.0.0.0:0").await?;
-        s.connect(addr).await?;
-        Ok(Client { s })
+    async fn connect(config: &Config, server: &ServerAddr) -> Result<Client> {
+        let client = Client::new(config, server).await?;
+        client.handshake().await?;
+        Ok(client)
+    }
+    /// Connects without joining the room
+    async fn new(config: &Config, server: &ServerAddr) -> Result<Client> {
+        let transport = Transport::connect(server).await?;
+        Ok(Client {
+            punch: transport.is_udp() && config.relay.is_none() && config.mode != Mode::Observer,
+            transport,
+            auth: config.key.as_deref().map(|k| Mutex::new(Auth::new(k))),
+            cipher: config.passphrase.as_deref().map(Cipher::new),
+            role: config.mode,
+            room: config.room.clone(),
+            peer_id: AtomicU32::new(0),
+            next_seq: AtomicU32::new(0),
+            started: Instant::now(),
+            server_rtt: Mutex::new(None),
+            links: Mutex::new(HashMap::new()),
+            batch: AtomicBool::new(false),
+            unbatched: Mutex::new(VecDeque::new()),
+            batch_us: config.batch_us,
+            mtu: config.mtu,
+            next_fragment: AtomicU32::new(0),
+            reassembler: Mutex::new(Reassembler::new(Component::Client)),
+            compressor: if config.compress {
+                Some(Mutex::new(Compressor::new(Component::Client)))
+            } else {
+                None
+            },
+            compress: AtomicBool::new(false),
+            header_contexts: AtomicBool::new(false),
+            tx_contexts: Mutex::new(Encoder::new(Component::Client)),
+            rx_contexts: Mutex::new(HashMap::new()),
+            direct: AtomicBool::new(false),
+            paths: Mutex::new(Paths::default()),
+        })
     }
+    fn hello(&self) -> protocol::FrameBody {
+        let mut capabilities = protocol::CAP_BATCH | protocol::CAP_HEADER_CONTEXTS;
+        if self.cipher.is_some() {
+            capabilities |= protocol::CAP_ENCRYPTION;
+        }
+        if self.punch {
+            capabilities |= protocol::CAP_DIRECT;
+        }
+        if self.compressor.is_some() {
+            capabilities |= protocol::CAP_COMPRESSION;
+        }
+        protocol::FrameBody::hello(self.role, &self.room, capabilities)
+    }
+    fn welcome(&self, peer_id: u32, capabilities: u32, software: &[u8]) {
+        log::info!(
+            "Joined room {:?} as peer {}, server version {}, capabilities {:#x}",
+            self.room,
+            peer_id,
+            String::from_utf8_lossy(software),
+            capabilities
+        );
+        self.peer_id.store(peer_id, Ordering::Relaxed);
+        let direct = capabilities & protocol::CAP_DIRECT != 0 && self.punch;
+        self.direct.store(direct, Ordering::Relaxed);
+        let batch = capabilities & protocol::CAP_BATCH != 0;
+        self.batch.store(batch, Ordering::Relaxed);
+        let compress = capabilities & protocol::CAP_COMPRESSION != 0 && self.compressor.is_some();
+        self.compress.store(compress, Ordering::Relaxed);
+        let header_contexts = capabilities & protocol::CAP_HEADER_CONTEXTS != 0;
+        self.header_contexts
+            .store(header_contexts, Ordering::Relaxed);
+        // the server may have restarted, reusing context ids and generations
+        self.rx_contexts.lock().unwrap().clear();
+    }
+    async fn handshake(&self) -> Result<()> {
+        use protocol::FrameBody;
+        for _ in 0..HANDSHAKE_RETRIES {
+            self.send(self.hello()).await?;
+            let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+            while let Ok(r) = tokio::time::timeout_at(deadline, self.recv_frame()).await {
+                match r? {
+                    FrameBody::Welcome {
+                        peer_id,
+                        capabilities,
+                        software,
+                    } => {
+                        self.welcome(peer_id, capabilities, &software);
+                        return Ok(());
+                    }
+                    FrameBody::Error { code, message } => {
+                        return Err(anyhow!(
+                            "Server refused us ({:?}): {}",
+                            code,
+                            String::from_utf8_lossy(&message)
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            log::warn!("No answer from server, retrying");
+        }
+        Err(anyhow!("Server didn't answer Hello"))
+    }
+    /// Sessions of our room, or of all rooms
+    async fn list_sessions(&self, all_rooms: bool) -> Result<Vec<protocol::SessionEntry>> {
+        use protocol::FrameBody;
+        let room = Some(self.room.as_str()).filter(|_| !all_rooms);
+        for _ in 0..HANDSHAKE_RETRIES {
+            self.send(FrameBody::list_sessions(room)?).await?;
+            let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+            while let Ok(r) = tokio::time::timeout_at(deadline, self.recv_frame()).await {
+                match r? {
+                    FrameBody::Sessions { sessions, .. } => return Ok(sessions),
+                    FrameBody::Error { code, message } => {
+                        return Err(anyhow!(
+                            "Server error ({:?}): {}",
+                            code,
+                            String::from_utf8_lossy(&message)
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            log::warn!("No answer from server, retrying");
+        }
+        Err(anyhow!("Server didn't answer"))
+    }
+    /// Receives the next frame for the device, handling control frames
     async fn recv(&self) -> Result<protocol::FrameBody> {
-        let mut buf = [0u8; 2048];
-        let len = self.s.recv(&mut buf).await?;
-        let buf = &buf[..len];
-        let (_, frame) = protocol::Frame::from_bytes((buf, 0))?;
-        Ok(frame.body)
+        use protocol::{ErrorCode, FrameBody};
+        loop {
+            match self.recv_frame().await? {
+                FrameBody::Welcome {
+                    peer_id,
+                    capabilities,
+                    software,
+                } => self.welcome(peer_id, capabilities, &software),
+                FrameBody::Peers { peers, .. } => self.paths.lock().unwrap().update(&peers),
+                FrameBody::Keepalive => {}
+                FrameBody::Ping {
+                    from,
+                    to,
+                    timestamp,
+                } => {
+                    let peer_id = self.peer_id.load(Ordering::Relaxed);
+                    if to == peer_id {
+                        self.heard(from);
+                        let pong = FrameBody::Pong {
+                            from: peer_id,
+                            to: from,
+                            timestamp,
+                        };
+                        self.send(pong).await?;
+                    }
+                }
+                FrameBody::Pong {
+                    from, timestamp, ..
+                } => self.pong(from, timestamp),
+                FrameBody::ContextResync { context } => {
+                    self.tx_contexts.lock().unwrap().resync(context)
+                }
+                FrameBody::Data {
+                    peer_id,
+                    seq,
+                    radio,
+                    ..
+                } if self.is_duplicate(peer_id, seq, radio.as_ref()) => {}
+                FrameBody::Goodbye { reason } => {
+                    return Err(anyhow!(
+                        "Server said goodbye: {}",
+                        String::from_utf8_lossy(&reason)
+                    ))
+                }
+                FrameBody::Error {
+                    code: ErrorCode::NotJoined,
+                    ..
+                } => {
+                    log::warn!("Server forgot us, joining again");
+                    self.send(self.hello()).await?;
+                }
+                FrameBody::Error { code, message } => {
+                    return Err(anyhow!(
+                        "Server error ({:?}): {}",
+                        code,
+                        String::from_utf8_lossy(&message)
+                    ))
+                }
+                body => return Ok(body),
+            }
+        }
+    }
+    async fn recv_frame(&self) -> Result<protocol::FrameBody> {
+        loop {
+            let unbatched = self.unbatched.lock().unwrap().pop_front();
+            if let Some((from, body)) = unbatched {
+                if let (Some(from), false) = (from, from_direct(&body)) {
+                    log::debug!("Dropped frame batched by direct peer {}", from);
+                    continue;
+                }
+                if let Some(body) = self.unpack(from, body).await? {
+                    return Ok(body);
+                }
+                continue;
+            }
+            let (buf, from) = self.transport.recv().await?;
+            metrics::rx(Component::Client, buf.len());
+            let buf = match &self.auth {
+                Some(auth) => match auth.lock().unwrap().open(&buf) {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        metrics::inc(&metrics::AUTH_FAILURES, Component::Client);
+                        log::warn!("Dropped datagram from {:?}: {}", from, e);
+                        continue;
+                    }
+                },
+                None => &buf,
+            };
+            let (_, frame) = match protocol::Frame::from_bytes((buf, 0)) {
+                Ok(f) => f,
+                Err(e) => {
+                    metrics::inc(&metrics::PARSE_ERRORS, Component::Client);
+                    log::warn!("Dropped bad frame from {:?}: {}", from, e);
+                    continue;
+                }
+            };
+            if let Some(from) = from {
+                use protocol::FrameBody;
+                let now = Instant::now();
+                let mut paths = self.paths.lock().unwrap();
+                match &frame.body {
+                    FrameBody::Punch { peer_id, heard } => {
+                        paths.punched(*peer_id, from, *heard != 0, now);
+                        continue;
+                    }
+                    // the frames of batches and fragments are checked once unpacked
+                    body if (from_direct(body)
+                        || matches!(
+                            body,
+                            FrameBody::Batch { .. } | FrameBody::Fragment { .. }
+                        ))
+                        && paths.received(from, now).is_some() => {}
+                    _ => {
+                        log::debug!("Dropped frame from unknown peer {}", from);
+                        continue;
+                    }
+                }
+            }
+            let frame = match frame.body {
+                protocol::FrameBody::Fragment {
+                    id,
+                    index,
+                    count,
+                    data,
+                    ..
+                } => {
+                    let now = Instant::now();
+                    let mut reassembler = self.reassembler.lock().unwrap();
+                    let bytes = match reassembler.add(from, id, index, count, data, now) {
+                        Some(bytes) => bytes,
+                        None => continue,
+                    };
+                    let frame = match protocol::Frame::from_bytes((&bytes, 0)) {
+                        Ok((_, frame))
+                            if !matches!(frame.body, protocol::FrameBody::Fragment { .. }) =>
+                        {
+                            frame
+                        }
+                        _ => {
+                            metrics::inc(&metrics::PARSE_ERRORS, Component::Client);
+                            log::warn!("Dropped bad reassembled frame from {:?}", from);
+                            continue;
+                        }
+                    };
+                    if let (Some(from), false) = (from, from_direct(&frame.body)) {
+                        log::debug!("Dropped frame reassembled from direct peer {}", from);
+                        continue;
+                    }
+                    frame
+                }
+                _ => frame,
+            };
+            if let protocol::FrameBody::Batch { frames, .. } = frame.body {
+                let mut unbatched = self.unbatched.lock().unwrap();
+                unbatched.extend(frames.into_iter().map(|f| (from, f.body)));
+                continue;
+            }
+            if let Some(body) = self.unpack(from, frame.body).await? {
+                return Ok(body);
+            }
+        }
+    }
+    /// Decompresses, expands and decrypts the frame from `from` as needed,
+    /// None if it is dropped
+    async fn unpack(
+        &self,
+        from: Option<SocketAddr>,
+        body: protocol::FrameBody,
+    ) -> Result<Option<protocol::FrameBody>> {
+        let body = match body {
+            protocol::FrameBody::Compressed { data } => match self.decompress(&data) {
+                Some(body) => body,
+                None => return Ok(None),
+            },
+            body => body,
+        };
+        let decoded = self
+            .rx_contexts
+            .lock()
+            .unwrap()
+            .entry(from)
+            .or_default()
+            .decode(body, Instant::now());
+        match decoded {
+            Decoded::Data(body) => Ok(self.open(body)),
+            Decoded::Resync(context) => {
+                // frames over direct paths don't use header contexts
+                if let Some(from) = from {
+                    log::debug!("Dropped frame of unknown header context from {}", from);
+                    return Ok(None);
+                }
+                log::debug!("Asking the server for header context {}", context);
+                self.send(protocol::FrameBody::ContextResync { context })
+                    .await?;
+                Ok(None)
+            }
+            Decoded::Dropped => Ok(None),
+        }
+    }
+    /// Decrypts the frame if needed, None if it is dropped
+    fn open(&self, body: protocol::FrameBody) -> Option<protocol::FrameBody> {
+        match (&self.cipher, body) {
+            (Some(cipher), body @ protocol::FrameBody::Encrypted { .. }) => {
+                match cipher.decrypt(body) {
+                    Ok(body) => return Some(body),
+                    Err(e) => log::warn!("Dropped encrypted frame: {}", e),
+                }
+            }
+            (None, protocol::FrameBody::Encrypted { .. }) => {
+                log::warn!("Dropped encrypted frame, passphrase is not set")
+            }
+            (Some(_), protocol::FrameBody::Data { .. }) => {
+                log::warn!("Dropped unencrypted frame, passphrase is set")
+            }
+            (_, body) => return Some(body),
+        }
+        None
+    }
+    /// The `Data` or `ContextData` frame in a `Compressed` one, None if it is
+    /// dropped
+    fn decompress(&self, data: &[u8]) -> Option<protocol::FrameBody> {
+        let compressor = match &self.compressor {
+            Some(compressor) => compressor,
+            None => {
+                log::warn!("Dropped compressed frame, compress is not set");
+                return None;
+            }
+        };
+        let plain = compressor.lock().unwrap().decompress(data);
+        match plain.and_then(|p| Ok(protocol::Frame::from_bytes((&p, 0))?.1)) {
+            Ok(frame) if frame.version != protocol::PROTOCOL_VERSION => {
+                log::warn!(
+                    "Dropped compressed frame of protocol version {}",
+                    frame.version
+                );
+                None
+            }
+            Ok(frame)
+                if matches!(
+                    frame.body,
+                    protocol::FrameBody::Data { .. } | protocol::FrameBody::ContextData { .. }
+                ) =>
+            {
+                Some(frame.body)
+            }
+            Ok(frame) => {
+                log::warn!("Dropped compressed {:?}", frame.frame_type);
+                None
+            }
+            Err(e) => {
+                metrics::inc(&metrics::PARSE_ERRORS, Component::Client);
+                log::warn!("Dropped compressed frame: {}", e);
+                None
+            }
+        }
+    }
+    /// Data frames for the server with header contexts and compressed, as
+    /// far as it takes them
+    fn pack(&self, body: protocol::FrameBody) -> Result<protocol::FrameBody> {
+        let body = if self.header_contexts.load(Ordering::Relaxed) {
+            self.tx_contexts.lock().unwrap().encode(body)
+        } else {
+            body
+        };
+        self.compress(body)
+    }
+    /// Compresses data frames for the server, if it takes them and they get smaller
+    fn compress(&self, body: protocol::FrameBody) -> Result<protocol::FrameBody> {
+        let compressor = match &self.compressor {
+            Some(c) if self.compress.load(Ordering::Relaxed) => c,
+            _ => return Ok(body),
+        };
+        if !matches!(
+            body,
+            protocol::FrameBody::Data { .. } | protocol::FrameBody::ContextData { .. }
+        ) {
+            return Ok(body);
+        }
+        let frame = protocol::Frame::try_from(body)?;
+        let compressed = compressor.lock().unwrap().compress(&frame.to_bytes()?);
+        Ok(match compressed {
+            Some(data) => protocol::FrameBody::Compressed { data },
+            None => frame.body,
+        })
+    }
+    /// A data frame with our id and the next sequence number
+    fn data(&self, packet: Packet) -> protocol::FrameBody {
+        protocol::FrameBody::Data {
+            peer_id: self.peer_id.load(Ordering::Relaxed),
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            channel: packet.channel,
+            radio: packet.radio,
+            data: packet.data,
+        }
+    }
+    fn heard(&self, peer_id: u32) {
+        let mut links = self.links.lock().unwrap();
+        links.entry(peer_id).or_insert_with(Link::new).last_rx = Instant::now();
+    }
+    /// Tracks the sequence number and signal of a data frame, true if we had
+    /// it already, e.g. over both a direct path and the relay
+    fn is_duplicate(&self, peer_id: u32, seq: u32, radio: Option<&RadioInfo>) -> bool {
+        let mut links = self.links.lock().unwrap();
+        let link = links.entry(peer_id).or_insert_with(Link::new);
+        link.last_rx = Instant::now();
+        if metrics::seq(Component::Client, &mut link.seq, seq) == Seq::Duplicate {
+            return true;
+        }
+        if let Some(radio) = radio {
+            link.signal.add(radio);
+        }
+        false
+    }
+    fn pong(&self, from: u32, timestamp: u64) {
+        let rtt = match self
+            .started
+            .elapsed()
+            .checked_sub(Duration::from_micros(timestamp))
+        {
+            Some(rtt) => rtt,
+            None => return,
+        };
+        if from == 0 {
+            log::debug!("RTT to server: {:?}", rtt);
+            metrics::RELAY_RTT.observe(rtt.as_secs_f64());
+            *self.server_rtt.lock().unwrap() = Some(rtt);
+        } else {
+            log::debug!("RTT to peer {}: {:?}", from, rtt);
+            metrics::PEER_RTT.observe(rtt.as_secs_f64());
+            let mut links = self.links.lock().unwrap();
+            let link = links.entry(from).or_insert_with(Link::new);
+            link.rtt = Some(rtt);
+            link.last_rx = Instant::now();
+        }
+    }
+    fn status(&self) -> ClientStatus {
+        let mut peers: Vec<_> = self
+            .links
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, link)| LinkStatus {
+                peer_id: *id,
+                rtt_ms: millis(link.rtt),
+                seq: link.seq.stats.clone(),
+                signal_dbm: link.signal.dbm(),
+            })
+            .collect();
+        peers.sort_by_key(|p| p.peer_id);
+        let compressor = self.compressor.as_ref().map(|c| c.lock().unwrap());
+        ClientStatus {
+            room: self.room.clone(),
+            peer_id: self.peer_id.load(Ordering::Relaxed),
+            role: self.role,
+            server_rtt_ms: millis(*self.server_rtt.lock().unwrap()),
+            peers,
+            tx_compression: compressor.as_ref().map(|c| c.sent.clone()),
+            rx_compression: compressor.as_ref().map(|c| c.received.clone()),
+        }
+    }
+    /// Largest frame that fits in one datagram
+    fn max_frame_len(&self) -> usize {
+        let overhead = if self.auth.is_some() { TRAILER_LEN } else { 0 };
+        self.mtu.saturating_sub(overhead)
+    }
+    fn batcher(&self) -> Batcher {
+        Batcher::new(self.batch_us, self.max_frame_len())
+    }
+    /// Datagrams carrying the frame, more than one if it exceeds the MTU
+    fn seal(&self, body: protocol::FrameBody) -> Result<Vec<Vec<u8>>> {
+        let frame = protocol::Frame::try_from(body)?;
+        let datagrams = fragment(
+            Component::Client,
+            frame.to_bytes()?,
+            self.max_frame_len(),
+            self.peer_id.load(Ordering::Relaxed),
+            || self.next_fragment.fetch_add(1, Ordering::Relaxed),
+        )?;
+        match &self.auth {
+            Some(auth) => {
+                let mut auth = auth.lock().unwrap();
+                datagrams.into_iter().map(|d| auth.seal(d)).collect()
+            }
+            None => Ok(datagrams),
+        }
+    }
+    /// Encrypts data frames if we have a passphrase
+    fn encrypt(&self, frame: protocol::FrameBody) -> Result<protocol::FrameBody> {
+        match (&self.cipher, frame) {
+            (Some(cipher), body @ protocol::FrameBody::Data { .. }) => cipher.encrypt(body),
+            (_, body) => Ok(body),
+        }
+    }
+    /// Sends a data frame, batched with the next ones if `batcher` is enabled
+    async fn send_data(&self, batcher: &mut Batcher, packet: Packet) -> Result<()> {
+        let body = self.data(packet);
+        if batcher.delay.is_none() || !self.batch.load(Ordering::Relaxed) {
+            return self.send(body).await;
+        }
+        let frame = protocol::Frame::try_from(self.encrypt(body)?)?;
+        if !batcher.fits(&frame) {
+            // sent on its own, after the frames batched before it
+            self.send_batch(batcher.take()).await?;
+            return self.send(frame.body).await;
+        }
+        if let Some(frames) = batcher.push(frame) {
+            self.send_batch(frames).await?;
+        }
+        Ok(())
+    }
+    /// Sends the frames of a batch, one by one over direct paths since the
+    /// peers there may not take batches
+    async fn send_batch(&self, frames: Vec<protocol::Frame>) -> Result<()> {
+        if frames.len() <= 1 {
+            for frame in frames {
+                self.send(frame.body).await?;
+            }
+            return Ok(());
+        }
+        let (direct, relay) = if self.direct.load(Ordering::Relaxed) {
+            self.paths.lock().unwrap().routes(Instant::now())
+        } else {
+            (vec![], true)
+        };
+        if !direct.is_empty() {
+            for frame in &frames {
+                for bytes in self.seal(frame.body.clone())? {
+                    for addr in &direct {
+                        self.send_to(&bytes, *addr).await;
+                    }
+                }
+            }
+        }
+        if relay {
+            let frames = frames
+                .into_iter()
+                .map(|f| self.pack(f.body).and_then(protocol::Frame::try_from))
+                .collect::<Result<_>>()?;
+            for bytes in self.seal(protocol::FrameBody::batch(frames))? {
+                self.transport.send(&bytes).await?;
+                metrics::tx(Component::Client, bytes.len());
+            }
+        }
+        Ok(())
     }
     async fn send(&self, frame: protocol::FrameBody) -> Result<()> {
-        let frame: protocol::Frame = frame.into();
-        let bytes = frame.to_bytes()?;
-        self.s.send(&bytes).await?;
+        let is_data = matches!(
+            frame,
+            protocol::FrameBody::Data { .. } | protocol::FrameBody::Encrypted { .. }
+        );
+        let body = self.encrypt(frame)?;
+        let (direct, relay) = if is_data && self.direct.load(Ordering::Relaxed) {
+            self.paths.lock().unwrap().routes(Instant::now())
+        } else {
+            (vec![], true)
+        };
+        if !direct.is_empty() {
+            for bytes in self.seal(body.clone())? {
+                for addr in &direct {
+                    self.send_to(&bytes, *addr).await;
+                }
+            }
+        }
+        if relay {
+            for bytes in self.seal(self.pack(body)?)? {
+                self.transport.send(&bytes).await?;
+                metrics::tx(Component::Client, bytes.len());
+            }
+        }
         Ok(())
     }
+    /// Sends to a peer directly, a failure only means the path is down
+    async fn send_to(&self, bytes: &[u8], addr: SocketAddr) {
+        match self.transport.send_to(bytes, addr).await {
+            Ok(_) => metrics::tx(Component::Client, bytes.len()),
+            Err(e) => log::debug!("Failed to send to {}: {}", addr, e),
+        }
+    }
+    /// Punches direct paths to the peers of the room, and tells the server
+    /// which peers are reached directly
+    fn spawn_direct(self: &Arc<Self>) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(PUNCH_INTERVAL);
+            let mut reported = vec![];
+            for tick in 0u64.. {
+                ticker.tick().await;
+                if !client.direct.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let now = Instant::now();
+                let (endpoints, up) = {
+                    let paths = client.paths.lock().unwrap();
+                    (paths.endpoints(now), paths.up(now))
+                };
+                let peer_id = client.peer_id.load(Ordering::Relaxed);
+                for (addr, heard) in endpoints {
+                    let punch = protocol::FrameBody::Punch {
+                        peer_id,
+                        heard: heard as u8,
+                    };
+                    match client.seal(punch) {
+                        Ok(punch) => {
+                            for bytes in &punch {
+                                client.send_to(bytes, addr).await;
+                            }
+                        }
+                        Err(e) => log::warn!("Failed to seal punch: {:?}", e),
+                    }
+                }
+                // repeated in case the server forgot
+                if up != reported || tick % 5 == 0 {
+                    let direct = protocol::FrameBody::Direct {
+                        peer_ids: up.clone(),
+                    };
+                    if let Err(e) = client.send(direct).await {
+                        log::warn!("Failed to send direct peers: {:?}", e);
+                    }
+                    reported = up;
+                }
+            }
+        });
+    }
+    /// Tells the server we leave, so it drops us right away
+    async fn goodbye(&self) {
+        if let Err(e) = self
+            .send(protocol::FrameBody::goodbye("shutting down"))
+            .await
+        {
+            log::warn!("Failed to say goodbye: {:?}", e);
+        }
+    }
+    /// Pings the server and the other peers, which also keeps us alive on
+    /// the server when there is no traffic, and logs the status now and then
+    fn spawn_keepalive(self: &Arc<Self>) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(protocol::KEEPALIVE_INTERVAL);
+            for tick in 1u64.. {
+                ticker.tick().await;
+                let mut to = vec![0];
+                to.extend(client.paths.lock().unwrap().peer_ids());
+                {
+                    let mut links = client.links.lock().unwrap();
+                    links.retain(|_, link| link.last_rx.elapsed() < LINK_TIMEOUT);
+                    to.extend(links.keys());
+                }
+                to.sort_unstable();
+                to.dedup();
+                let from = client.peer_id.load(Ordering::Relaxed);
+                let timestamp = client.started.elapsed().as_micros() as u64;
+                for to in to {
+                    let ping = protocol::FrameBody::Ping {
+                        from,
+                        to,
+                        timestamp,
+                    };
+                    if let Err(e) = client.send(ping).await {
+                        log::warn!("Failed to send ping: {:?}", e);
+                    }
+                }
+                if tick % STATUS_TICKS == 0 {
+                    log::info!("{}", client.status().report());
+                }
+            }
+        });
+    }
+}
+
+/// Frames direct peers may send, the others only come from the server
+fn from_direct(body: &protocol::FrameBody) -> bool {
+    matches!(
+        body,
+        protocol::FrameBody::Data { .. }
+            | protocol::FrameBody::Encrypted { .. }
+            | protocol::FrameBody::Compressed { .. }
+    )
 }
 
 fn is_ack(frame: &ieee80211::Frame) -> bool {
@@ -152,11 +1082,7 @@ fn is_ack(frame: &ieee80211::Frame) -> bool {
         &frame.frame_control.frame_type,
         &frame.frame_control.sub_type,
     );
-    if let (FrameType::Control, 13) = (frame_type, sub_type) {
-        true
-    } else {
-        false
-    }
+    matches!((frame_type, sub_type), (FrameType::Control, 13))
 }
 
 fn get_action_ssid(data: &[u8]) -> Option<String> {
@@ -168,7 +1094,8 @@ fn get_action_ssid(data: &[u8]) -> Option<String> {
 
     if let (FrameType::Management, 13) = (frame_type, sub_type) {
         // Nintendo action frame
-        if rest[0] == 0x7f
+        if rest.len() >= 44
+            && rest[0] == 0x7f
             && rest[1..4] == [0x00, 0x22, 0xaa]
             && rest[4..12] == [0x04, 0x00, 0x01, 0x01, 0, 0, 0, 0]
         {
@@ -178,6 +1105,23 @@ fn get_action_ssid(data: &[u8]) -> Option<String> {
     None
 }
 
+/// Decodes the session advertised by a Nintendo action frame
+fn get_action_session(data: &[u8], channel: u32) -> Option<protocol::SessionInfo> {
+    get_action_ssid(data)?;
+    let (frame, rest) = parse_ieee80211(data).ok()?;
+    let mut session_id = [0; 16];
+    session_id.copy_from_slice(&rest[28..44]);
+    let mut local_communication_id = [0; 8];
+    local_communication_id.copy_from_slice(&rest[12..20]);
+    Some(protocol::SessionInfo {
+        session_id,
+        channel,
+        host: frame.addr2?,
+        local_communication_id: u64::from_be_bytes(local_communication_id),
+        scene_id: u16::from_be_bytes([rest[22], rest[23]]),
+    })
+}
+
 fn get_probe_ssid(data: &[u8]) -> Option<String> {
     let (frame, rest) = parse_ieee80211(data).ok()?;
     let (frame_type, sub_type) = (
@@ -195,7 +1139,11 @@ fn get_probe_ssid(data: &[u8]) -> Option<String> {
     None
 }
 
-async fn host_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
+async fn host_main(
+    client: Arc<Client>,
+    wlan_play: &mut WlanPlay,
+    capture: &mut Capture,
+) -> Result<()> {
     use protocol::FrameBody;
     let ns = loop {
         let ns = wlan_play.find_switch().await?;
@@ -206,59 +1154,79 @@ async fn host_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
     log::info!("Found NS: {:#?}", ns);
     let sta = ns.values().next().unwrap();
     wlan_play.set_station(sta.clone()).await?;
+    // when each session was last published
+    let mut published = HashMap::<[u8; 16], Instant>::new();
+    let mut batcher = client.batcher();
 
     loop {
         select! {
             cr = client.recv() => {
-                match cr? {
-                    FrameBody::Keepalive => {}
-                    FrameBody::Data { channel, data } => {
-                        wlan_play.dev.send(Packet {
-                            channel,
-                            data,
-                        }).await?;
-                    }
-                };
+                if let FrameBody::Data { channel, data, radio, .. } = cr? {
+                    capture.write(channel, &data, radio.as_ref(), "relayed");
+                    wlan_play.dev.send(Packet {
+                        channel,
+                        data,
+                        radio,
+                    }).await?;
+                }
             }
             dr = wlan_play.dev.try_next() => {
                 let p = dr?.ok_or(anyhow!("Device stopped"))?;
-                client.send(protocol::FrameBody::Data {
-                    channel: p.channel,
-                    data: p.data,
-                }).await?;
+                if let Some(session) = get_action_session(&p.data, p.channel) {
+                    let now = Instant::now();
+                    let due = match published.get(&session.session_id) {
+                        Some(t) => now.duration_since(*t) >= protocol::SESSION_INTERVAL,
+                        None => true,
+                    };
+                    if due {
+                        published.retain(|_, t| {
+                            now.duration_since(*t) < 3 * protocol::SESSION_INTERVAL
+                        });
+                        published.insert(session.session_id, now);
+                        client.send(FrameBody::Session { session }).await?;
+                    }
+                }
+                capture.write(p.channel, &p.data, p.radio.as_ref(), "captured");
+                client.send_data(&mut batcher, p).await?;
+            }
+            _ = sleep_until(batcher.deadline.unwrap_or_else(Instant::now)), if batcher.deadline.is_some() => {
+                client.send_batch(batcher.take()).await?;
             }
         }
     }
 }
 
-async fn station_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
+async fn station_main(
+    client: Arc<Client>,
+    wlan_play: &mut WlanPlay,
+    capture: &mut Capture,
+) -> Result<()> {
     use protocol::FrameBody;
-    client.send(FrameBody::Keepalive).await?;
 
     let mut channel_has_set = false;
     let mut ssids = HashSet::<String>::new();
     let mut stations = HashSet::<Mac>::new();
+    let mut batcher = client.batcher();
 
     loop {
         select! {
             cr = client.recv() => {
-                match cr? {
-                    FrameBody::Keepalive => {}
-                    FrameBody::Data { channel, data } => {
-                        if !channel_has_set {
-                            log::info!("Set channel to {}", channel);
-                            wlan_play.dev.set_channel(channel).await?;
-                            channel_has_set = true;
-                        }
-                        if let Some(ssid) = get_action_ssid(&data) {
-                            ssids.insert(ssid);
-                        }
-                        wlan_play.dev.send(Packet {
-                            channel,
-                            data,
-                        }).await?;
+                if let FrameBody::Data { channel, data, radio, .. } = cr? {
+                    if !channel_has_set {
+                        log::info!("Set channel to {}", channel);
+                        wlan_play.dev.set_channel(channel).await?;
+                        channel_has_set = true;
                     }
-                };
+                    if let Some(ssid) = get_action_ssid(&data) {
+                        ssids.insert(ssid);
+                    }
+                    capture.write(channel, &data, radio.as_ref(), "relayed");
+                    wlan_play.dev.send(Packet {
+                        channel,
+                        data,
+                        radio,
+                    }).await?;
+                }
             }
             dr = wlan_play.dev.try_next() => {
                 let p = dr?.ok_or(anyhow!("Device stopped"))?;
@@ -267,10 +1235,8 @@ async fn station_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
                     stations.insert(frame.addr2.as_ref().unwrap().clone());
                 }
                 if is_ack(&frame) || stations.iter().any(|k| packet_has_mac(&frame, k)) {
-                    client.send(FrameBody::Data {
-                        channel: p.channel,
-                        data: p.data,
-                    }).await?;
+                    capture.write(p.channel, &p.data, p.radio.as_ref(), "captured");
+                    client.send_data(&mut batcher, p).await?;
                 }
                 // if let Some(true) = frame.addr2.as_ref().map(|src| stations.contains(src)) {
                 //     client.send(protocol::FrameBody::Data {
@@ -279,6 +1245,9 @@ async fn station_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
                 //     }).await?;
                 // }
             }
+            _ = sleep_until(batcher.deadline.unwrap_or_else(Instant::now)), if batcher.deadline.is_some() => {
+                client.send_batch(batcher.take()).await?;
+            }
         };
     }
 
@@ -286,29 +1255,221 @@ async fn station_main(client: Client, mut wlan_play: WlanPlay) -> Result<()> {
     // Ok(())
 }
 
+/// What an observer received since the last report
+#[derive(Default)]
+struct ObserverStats {
+    frames: u64,
+    bytes: u64,
+    channels: BTreeMap<u32, u64>,
+    transmitters: HashSet<Mac>,
+    sessions: HashSet<String>,
+    /// signal strength per site, by peer id
+    signals: BTreeMap<u32, Signal>,
+}
+
+impl ObserverStats {
+    fn add(&mut self, peer_id: u32, channel: u32, data: &[u8], radio: Option<&RadioInfo>) {
+        self.frames += 1;
+        if let Some(radio) = radio {
+            self.signals.entry(peer_id).or_default().add(radio);
+        }
+        self.bytes += data.len() as u64;
+        *self.channels.entry(channel).or_default() += 1;
+        if let Ok((frame, _)) = parse_ieee80211(data) {
+            self.transmitters.extend(frame.addr2);
+        }
+        self.sessions.extend(get_action_ssid(data));
+    }
+    fn report(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let signals: BTreeMap<_, _> = self
+            .signals
+            .iter()
+            .filter_map(|(id, s)| Some((id, s.dbm()?)))
+            .collect();
+        format!(
+            "{:.1} frames/s, {:.1} kB/s, frames per channel {:?}, {} transmitters, sessions {:?}, dBm per peer {:?}",
+            self.frames as f64 / secs,
+            self.bytes as f64 / secs / 1000.0,
+            self.channels,
+            self.transmitters.len(),
+            self.sessions,
+            signals
+        )
+    }
+}
+
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn observer_main(client: Arc<Client>, capture: &mut Capture) -> Result<()> {
+    use protocol::FrameBody;
+
+    let mut stats = ObserverStats::default();
+    let mut since = Instant::now();
+    let mut ticker = interval(STATS_INTERVAL);
+    ticker.tick().await;
+    loop {
+        select! {
+            cr = client.recv() => {
+                if let FrameBody::Data { peer_id, channel, data, radio, .. } = cr? {
+                    capture.write(channel, &data, radio.as_ref(), "relayed");
+                    stats.add(peer_id, channel, &data, radio.as_ref());
+                }
+            }
+            _ = ticker.tick() => {
+                log::info!("{}", stats.report(since.elapsed()));
+                stats = ObserverStats::default();
+                since = Instant::now();
+                capture.flush();
+            }
+        }
+    }
+}
+
+async fn sessions_main(config: &Config, all_rooms: bool) -> Result<()> {
+    let server = config
+        .server
+        .as_ref()
+        .ok_or_else(|| anyhow!("`server` must be set"))?;
+    let client = Client::new(config, server).await?;
+    let sessions = client.list_sessions(all_rooms).await?;
+    if sessions.is_empty() {
+        println!("No sessions");
+    }
+    for entry in sessions {
+        let s = &entry.session;
+        println!(
+            "room {:?}, peer {}: session {} of {:016x} scene {}, channel {}, host {:?}",
+            entry.room(),
+            entry.peer_id,
+            hex::encode(s.session_id),
+            s.local_communication_id,
+            s.scene_id,
+            s.channel,
+            s.host
+        );
+    }
+    Ok(())
+}
+
+async fn status_main(addr: SocketAddr) -> Result<()> {
+    let uri = format!("http://{}/status", addr).parse()?;
+    let resp = hyper::Client::new().get(uri).await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("{} answered {}", addr, resp.status()));
+    }
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    let status: serde_json::Value = serde_json::from_slice(&body)?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
 pub async fn main(opt: ClientOpt) -> Result<()> {
     use tokio::fs::read;
     use toml::from_slice;
 
-    let config: Config = from_slice(&read(opt.cfg).await?)?;
-    let wlan_play = WlanPlay::new(&config, opt.pcap).await?;
-    let client = Client::connect(config.server).await?;
+    if let Some(ClientCmd::Status { addr }) = opt.cmd {
+        return status_main(addr).await;
+    }
+    let mut config: Config = from_slice(&read(opt.cfg).await?)?;
+    if let Some(ClientCmd::Sessions { all }) = opt.cmd {
+        return sessions_main(&config, all).await;
+    }
+    metrics::init_client();
+    if let Some(addr) = opt.relay {
+        config.relay.get_or_insert_with(Default::default).udp = vec![addr];
+    }
+    let mut relay_task = None;
+    let (stop_relay, relay_stopped) = oneshot::channel::<()>();
+    let server = match config.relay.as_mut() {
+        Some(relay_config) => {
+            if relay_config.keys.is_empty() {
+                relay_config.keys.extend(config.key.clone());
+            }
+            let relay = Relay::bind(relay_config).await?;
+            let addr = relay.local_addr()?;
+            // stopped by us once we said goodbye to it
+            relay_task = Some(tokio::spawn(relay.run(async {
+                let _ = relay_stopped.await;
+                Ok(())
+            })));
+            ServerAddr::Udp(addr.to_string())
+        }
+        None => config
+            .server
+            .clone()
+            .ok_or_else(|| anyhow!("Either `server` or `relay` must be set"))?,
+    };
+    let mut wlan_play = match config.mode {
+        Mode::Observer => None,
+        Mode::Host | Mode::Station => Some(WlanPlay::new(&config).await?),
+    };
+    let mut capture = Capture::create(opt.pcap)?;
+    let client = Arc::new(Client::connect(&config, &server).await?);
+    client.spawn_keepalive();
+    client.spawn_direct();
+    if let Some(addr) = opt.metrics {
+        let client = client.clone();
+        let status = move || serde_json::to_value(client.status()).unwrap_or_default();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, status).await {
+                log::error!("Metrics server stopped: {:?}", e);
+            }
+        });
+    }
 
-    match config.mode {
-        Mode::Host => {
-            host_main(client, wlan_play).await?;
+    let run = async {
+        match (config.mode, wlan_play.as_mut()) {
+            (Mode::Host, Some(w)) => host_main(client.clone(), w, &mut capture).await,
+            (Mode::Station, Some(w)) => station_main(client.clone(), w, &mut capture).await,
+            _ => observer_main(client.clone(), &mut capture).await,
         }
-        Mode::Station => {
-            station_main(client, wlan_play).await?;
+    };
+    let relay = async {
+        match relay_task.as_mut() {
+            Some(relay_task) => relay_task.await?,
+            None => futures::future::pending().await,
         }
     };
-
-    Ok(())
+    let result = select! {
+        r = run => r,
+        r = relay => r,
+        r = signal::shutdown() => {
+            log::info!("Shutting down");
+            r
+        }
+    };
+    client.goodbye().await;
+    capture.flush();
+    if let Some(wlan_play) = wlan_play {
+        if let Err(e) = wlan_play.stop().await {
+            log::warn!("Failed to stop the agent: {:?}", e);
+        }
+    }
+    if let Some(relay_task) = relay_task.filter(|t| !t.is_finished()) {
+        let _ = stop_relay.send(());
+        match timeout(RELAY_SHUTDOWN_TIMEOUT, relay_task).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(e))) => log::warn!("Relay failed to shut down: {:?}", e),
+            Ok(Err(e)) => log::warn!("Relay failed to shut down: {:?}", e),
+            Err(_) => log::warn!("Relay didn't shut down in time"),
+        }
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryInto;
+
+    const ACTION_FRAME: [u8; 68] = [
+        0xD0, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x04, 0x03, 0xD6, 0x28, 0xA3,
+        0xAC, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x40, 0x2E, 0x7F, 0x00, 0x22, 0xAA, 0x04, 0x00,
+        0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x29, 0xA6, 0x4B, 0x95, 0x8B, 0x63, 0xD3, 0xE6,
+        0x7E, 0x83, 0x84, 0x88, 0x3F, 0x02, 0x4F, 0x76,
+    ];
 
     #[test]
     fn test_get_action_ssid() {
@@ -325,6 +1486,74 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_action_ssid_truncated() {
+        assert_eq!(get_action_ssid(&ACTION_FRAME[..50]), None);
+    }
+
+    #[test]
+    fn test_get_action_session() {
+        let session = get_action_session(&ACTION_FRAME, 6).unwrap();
+        assert_eq!(
+            hex::encode(session.session_id),
+            "29a64b958b63d3e67e8384883f024f76"
+        );
+        assert_eq!(session.channel, 6);
+        assert_eq!(format!("{:?}", session.host), "04:03:d6:28:a3:ac");
+        assert_eq!(session.local_communication_id, 0x0100_0000_0000_1000);
+        assert_eq!(session.scene_id, 1);
+    }
+
+    #[test]
+    fn test_observer_stats() {
+        let mut stats = ObserverStats::default();
+        let radio = RadioInfo {
+            signal: -50,
+            ..Default::default()
+        };
+        stats.add(1, 6, &ACTION_FRAME, Some(&radio));
+        stats.add(1, 6, &ACTION_FRAME, None);
+        stats.add(
+            2,
+            11,
+            &[0xD4, 0x00, 0x00, 0x00, 0x60, 0x6B, 0xFF, 0x28, 0xFA, 0x83],
+            None,
+        );
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.channels[&6], 2);
+        assert_eq!(stats.transmitters.len(), 1);
+        assert_eq!(stats.sessions.len(), 1);
+        let report = stats.report(Duration::from_secs(1));
+        assert!(report.starts_with("3.0 frames/s"));
+        assert!(report.ends_with("dBm per peer {1: -50.0}"));
+    }
+
+    #[test]
+    fn test_batcher() {
+        let frame = |len| -> protocol::Frame {
+            protocol::FrameBody::Data {
+                peer_id: 1,
+                seq: 0,
+                channel: 6,
+                radio: None,
+                data: vec![0; len],
+            }
+            .try_into()
+            .unwrap()
+        };
+        assert_eq!(Batcher::new(0, 1200).delay, None);
+        let mut batcher = Batcher::new(500, 1200);
+        assert_eq!(batcher.push(frame(100)), None);
+        assert!(batcher.deadline.is_some());
+        assert_eq!(batcher.push(frame(900)), None);
+        assert_eq!(batcher.push(frame(300)).map(|f| f.len()), Some(2));
+        assert_eq!(batcher.take().len(), 1);
+        assert_eq!(batcher.deadline, None);
+        assert!(!batcher.fits(&frame(2000)));
+        assert_eq!(batcher.push(frame(2000)), None);
+        assert_eq!(batcher.push(frame(100)).map(|f| f.len()), Some(1));
+    }
+
     #[test]
     fn test_get_probe_ssid() {
         let data = [
@@ -0,0 +1,183 @@
+use super::fragment::MAX_FRAME_LEN;
+use crate::metrics::{self, Component};
+use anyhow::{anyhow, Result};
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, deflate_flags, CompressorOxide, TDEFLFlush,
+    TDEFLStatus,
+};
+use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use serde_derive::Serialize;
+
+/// zlib-like level, fast enough for every relayed frame
+const LEVEL: i32 = 6;
+
+/// Bytes found in most frames of a Nintendo LDN session, the compressor
+/// starts each frame with them in its window. The most common ones are last,
+/// where they are the cheapest to refer to.
+const DICTIONARY: &[u8] = &[
+    // probe request to broadcast, SSID, supported and extended rates
+    0x40, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x00, 0x00, 0x01, 0x08, 0x82, 0x84, 0x8b, 0x96, 0x0c, 0x12, 0x18, 0x24, 0x32, 0x04, 0x30, 0x48,
+    0x60, 0x6c, //
+    // beacon, interval and capabilities, SSID of hex digits
+    0x80, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x31, 0x04, 0x00, 0x20,
+    b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'a', b'b', b'c', b'd', b'e',
+    b'f', //
+    // DS parameter set, TIM, RSN with CCMP and PSK
+    0x03, 0x01, 0x06, 0x05, 0x04, 0x00, 0x01, 0x00, 0x00, 0x30, 0x14, 0x01, 0x00, 0x00, 0x0f, 0xac,
+    0x04, 0x01, 0x00, 0x00, 0x0f, 0xac, 0x04, 0x01, 0x00, 0x00, 0x0f, 0xac, 0x02, 0x0c,
+    0x00, //
+    // Nintendo vendor element
+    0xdd, 0x00, 0x22, 0xaa, //
+    // LLC/SNAP with ARP, and with IPv4 over UDP between 169.254.0.0/16 addresses
+    0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01,
+    0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00,
+    0x40, 0x11, 0x00, 0x00, 0xa9, 0xfe, 0x00, 0x01, 0xa9, 0xfe, 0xff, 0xff, //
+    // control frames: block ack request, block ack, ACK
+    0x84, 0x00, 0x00, 0x00, 0x94, 0x00, 0x00, 0x00, 0xd4, 0x00, 0x00, 0x00, //
+    // protected QoS data to and from the AP, with the CCMP header
+    0x88, 0x41, 0x00, 0x00, 0x88, 0x42, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00,
+    0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, //
+    // Nintendo action frame advertising a session
+    0xd0, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x7f, 0x00, 0x22, 0xaa, 0x04, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Sizes of the data frames compressed or decompressed
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct CompressionStats {
+    pub frames: u64,
+    pub plain_bytes: u64,
+    pub compressed_bytes: u64,
+    /// plain bytes per compressed byte
+    pub ratio: Option<f64>,
+}
+
+impl CompressionStats {
+    pub fn add(&mut self, plain: usize, compressed: usize) {
+        self.frames += 1;
+        self.plain_bytes += plain as u64;
+        self.compressed_bytes += compressed as u64;
+        self.ratio = Some(self.plain_bytes as f64 / self.compressed_bytes.max(1) as f64);
+    }
+}
+
+/// Compresses serialized `Data` frames into the payload of
+/// `FrameBody::Compressed`, raw deflate primed with `DICTIONARY`.
+pub struct Compressor {
+    deflate: CompressorOxide,
+    inflate: DecompressorOxide,
+    /// `DICTIONARY` as a stored deflate block, put before the received
+    /// payload so its references to the dictionary resolve
+    prefix: Vec<u8>,
+    out: Vec<u8>,
+    pub sent: CompressionStats,
+    pub received: CompressionStats,
+    component: Component,
+}
+
+impl Compressor {
+    pub fn new(component: Component) -> Compressor {
+        let len = DICTIONARY.len() as u16;
+        let mut prefix = vec![0];
+        prefix.extend_from_slice(&len.to_le_bytes());
+        prefix.extend_from_slice(&(!len).to_le_bytes());
+        prefix.extend_from_slice(DICTIONARY);
+        Compressor {
+            // raw deflate. Frames are too short to pay for dynamic Huffman tables.
+            deflate: CompressorOxide::new(
+                create_comp_flags_from_zip_params(LEVEL, -15, 0)
+                    | deflate_flags::TDEFL_FORCE_ALL_STATIC_BLOCKS,
+            ),
+            inflate: DecompressorOxide::new(),
+            prefix,
+            out: vec![0; DICTIONARY.len() + MAX_FRAME_LEN],
+            sent: CompressionStats::default(),
+            received: CompressionStats::default(),
+            component,
+        }
+    }
+    /// The compressed frame, None if it doesn't get any smaller
+    pub fn compress(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        self.deflate.reset();
+        let (status, _, _) = compress(
+            &mut self.deflate,
+            DICTIONARY,
+            &mut self.out,
+            TDEFLFlush::Sync,
+        );
+        if status != TDEFLStatus::Okay {
+            return None;
+        }
+        let mut out = vec![0; frame.len()];
+        match compress(&mut self.deflate, frame, &mut out, TDEFLFlush::Finish) {
+            (TDEFLStatus::Done, _, len) if len < frame.len() => {
+                out.truncate(len);
+                self.sent.add(frame.len(), len);
+                metrics::COMPRESSION_PLAIN_BYTES
+                    .with_label_values(&["tx", self.component.label()])
+                    .inc_by(frame.len() as u64);
+                metrics::COMPRESSION_COMPRESSED_BYTES
+                    .with_label_values(&["tx", self.component.label()])
+                    .inc_by(len as u64);
+                Some(out)
+            }
+            _ => None,
+        }
+    }
+    /// The frame `compress` was given, at most `MAX_FRAME_LEN` bytes
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let input = [&self.prefix[..], data].concat();
+        self.inflate.init();
+        let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        match decompress(&mut self.inflate, &input, &mut self.out, 0, flags) {
+            (TINFLStatus::Done, _, len) if len >= DICTIONARY.len() => {
+                let frame = self.out[DICTIONARY.len()..len].to_vec();
+                self.received.add(frame.len(), data.len());
+                metrics::COMPRESSION_PLAIN_BYTES
+                    .with_label_values(&["rx", self.component.label()])
+                    .inc_by(frame.len() as u64);
+                metrics::COMPRESSION_COMPRESSED_BYTES
+                    .with_label_values(&["rx", self.component.label()])
+                    .inc_by(data.len() as u64);
+                Ok(frame)
+            }
+            (status, _, _) => Err(anyhow!("Failed to decompress: {:?}", status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let mut alice = Compressor::new(Component::Client);
+        let mut bob = Compressor::new(Component::Client);
+        let frame = [
+            0xd0, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x04, 0x03, 0xd6, 0x28,
+            0xa3, 0xac, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x40, 0x2e, 0x7f, 0x00, 0x22, 0xaa,
+            0x04, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let compressed = alice.compress(&frame).unwrap();
+        assert!(compressed.len() < frame.len() / 2);
+        assert_eq!(bob.decompress(&compressed).unwrap(), frame);
+        // the state doesn't carry over from one frame to the next
+        let compressed = alice.compress(&frame[..30]).unwrap();
+        assert_eq!(bob.decompress(&compressed).unwrap(), &frame[..30]);
+        assert_eq!(alice.sent.frames, 2);
+        assert_eq!(bob.received, alice.sent);
+
+        assert_eq!(alice.compress(&[0x12, 0x34]), None);
+        assert!(bob.decompress(&[0xff; 8]).is_err());
+        // larger than any frame
+        let mut big = Compressor::new(Component::Client).deflate;
+        big.reset();
+        let mut out = vec![0; 4096];
+        let zeros = vec![0; MAX_FRAME_LEN + 1];
+        let (_, _, len) = compress(&mut big, &zeros, &mut out, TDEFLFlush::Finish);
+        assert!(bob.decompress(&out[..len]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use super::protocol::{Frame, FrameBody, NONCE_LEN};
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use deku::prelude::*;
+use sha2::Sha256;
+use std::convert::TryFrom;
+
+const KDF_SALT: &[u8] = b"wlan-play e2e";
+const KDF_ROUNDS: u32 = 100_000;
+
+/// End-to-end encryption between clients sharing a passphrase.
+///
+/// The relay only sees `FrameBody::Encrypted`, the inner frame is encrypted
+/// with XChaCha20-Poly1305 so random nonces are safe with many senders.
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(passphrase: &str) -> Cipher {
+        let key =
+            pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(passphrase.as_bytes(), KDF_SALT, KDF_ROUNDS);
+        Cipher {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+    /// The sender and sequence number of `Data` stay visible to the relay
+    pub fn encrypt(&self, body: FrameBody) -> Result<FrameBody> {
+        let (peer_id, seq) = match body {
+            FrameBody::Data { peer_id, seq, .. } => (peer_id, seq),
+            _ => (0, 0),
+        };
+        let plain = Frame::try_from(body)?.to_bytes()?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let data = self
+            .aead
+            .encrypt(&nonce, plain.as_ref())
+            .map_err(|_| anyhow!("Failed to encrypt"))?;
+        let mut n = [0u8; NONCE_LEN];
+        n.copy_from_slice(&nonce);
+        Ok(FrameBody::Encrypted {
+            peer_id,
+            seq,
+            nonce: n,
+            data,
+        })
+    }
+    pub fn decrypt(&self, body: FrameBody) -> Result<FrameBody> {
+        let (nonce, data) = match body {
+            FrameBody::Encrypted { nonce, data, .. } => (nonce, data),
+            _ => return Err(anyhow!("Not encrypted")),
+        };
+        let plain = self
+            .aead
+            .decrypt(XNonce::from_slice(&nonce), data.as_ref())
+            .map_err(|_| anyhow!("Failed to decrypt, is the passphrase the same?"))?;
+        let (_, frame) = Frame::from_bytes((&plain, 0))?;
+        Ok(frame.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::RadioInfo;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let alice = Cipher::new("passphrase");
+        let bob = Cipher::new("passphrase");
+        let eve = Cipher::new("wrong");
+        let radio = Some(RadioInfo {
+            signal: -40,
+            ..Default::default()
+        });
+        let body = FrameBody::Data {
+            peer_id: 1,
+            seq: 2,
+            channel: 6,
+            radio,
+            data: vec![1, 2, 3],
+        };
+        let encrypted = alice.encrypt(body).unwrap();
+        assert!(matches!(
+            encrypted,
+            FrameBody::Encrypted {
+                peer_id: 1,
+                seq: 2,
+                ..
+            }
+        ));
+        assert!(eve
+            .decrypt(alice.encrypt(FrameBody::Keepalive).unwrap())
+            .is_err());
+        assert_eq!(
+            bob.decrypt(encrypted).unwrap(),
+            FrameBody::Data {
+                peer_id: 1,
+                seq: 2,
+                channel: 6,
+                radio,
+                data: vec![1, 2, 3],
+            }
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Direct UDP paths between clients.
+//!
+//! The server sends each client the public endpoints of the other peers of
+//! its room, and both sides keep sending `Punch` to each other, only accepted
+//! from the endpoint the server announced. A punch says whether its sender
+//! hears the receiver, and a path is used once both sides hear each other.
+//! Peers without a working path are still reached through the server.
+use super::protocol::PeerEndpoint;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often `Punch` is sent to each peer
+pub const PUNCH_INTERVAL: Duration = Duration::from_secs(1);
+/// A direct path is down when nothing arrived over it, or the peer didn't say
+/// it hears us, for this long
+pub const DIRECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Path {
+    endpoint: SocketAddr,
+    last_rx: Option<Instant>,
+    /// when the peer last said it hears us
+    last_heard: Option<Instant>,
+}
+
+fn recent(t: Option<Instant>, now: Instant) -> bool {
+    matches!(t, Some(t) if now - t <= DIRECT_TIMEOUT)
+}
+
+impl Path {
+    fn new(endpoint: SocketAddr) -> Path {
+        Path {
+            endpoint,
+            last_rx: None,
+            last_heard: None,
+        }
+    }
+    /// Frames go through in both directions
+    fn is_up(&self, now: Instant) -> bool {
+        recent(self.last_rx, now) && recent(self.last_heard, now)
+    }
+}
+
+#[derive(Default)]
+pub struct Paths {
+    /// other peers of the room, with a path if they can be reached directly
+    peers: HashMap<u32, Option<Path>>,
+    /// whether the server sent the peers of the room yet
+    known: bool,
+}
+
+impl Paths {
+    /// Replaces the peers of the room, keeping the state of known paths
+    pub fn update(&mut self, peers: &[PeerEndpoint]) {
+        let mut old = std::mem::take(&mut self.peers);
+        for p in peers {
+            let path = match (old.remove(&p.peer_id).flatten(), p.endpoint()) {
+                (Some(path), Some(_)) => Some(path),
+                (None, Some(endpoint)) => Some(Path::new(endpoint)),
+                (_, None) => None,
+            };
+            self.peers.insert(p.peer_id, path);
+        }
+        self.known = true;
+    }
+    /// Other peers of the room, reachable directly or not
+    pub fn peer_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<_> = self.peers.keys().cloned().collect();
+        ids.sort_unstable();
+        ids
+    }
+    /// `Punch` from `peer_id` arrived from `from`, saying whether it hears us.
+    /// False if `from` isn't the endpoint announced for it.
+    pub fn punched(&mut self, peer_id: u32, from: SocketAddr, heard: bool, now: Instant) -> bool {
+        match self.peers.get_mut(&peer_id) {
+            Some(Some(path)) if path.endpoint == from => {
+                let was_up = path.is_up(now);
+                path.last_rx = Some(now);
+                if heard {
+                    path.last_heard = Some(now);
+                }
+                if !was_up && path.is_up(now) {
+                    log::info!("Direct path to peer {} at {} is up", peer_id, from);
+                }
+                true
+            }
+            Some(Some(path)) => {
+                log::debug!(
+                    "Ignored punch of peer {} from {} instead of {}",
+                    peer_id,
+                    from,
+                    path.endpoint
+                );
+                false
+            }
+            _ => false,
+        }
+    }
+    /// A frame arrived from `from`, returns the peer if it is known
+    pub fn received(&mut self, from: SocketAddr, now: Instant) -> Option<u32> {
+        let (id, path) = self
+            .peers
+            .iter_mut()
+            .find_map(|(id, p)| p.as_mut().filter(|p| p.endpoint == from).map(|p| (*id, p)))?;
+        path.last_rx = Some(now);
+        Some(id)
+    }
+    /// Endpoints to punch, and whether we hear each of them
+    pub fn endpoints(&self, now: Instant) -> Vec<(SocketAddr, bool)> {
+        self.peers
+            .values()
+            .flatten()
+            .map(|p| (p.endpoint, recent(p.last_rx, now)))
+            .collect()
+    }
+    /// Peers reached directly
+    pub fn up(&self, now: Instant) -> Vec<u32> {
+        let mut ids: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| matches!(p, Some(p) if p.is_up(now)))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+    /// Endpoints to send a data frame to directly, and whether it must also
+    /// go through the server
+    pub fn routes(&self, now: Instant) -> (Vec<SocketAddr>, bool) {
+        let mut direct = vec![];
+        let mut relay = !self.known;
+        for path in self.peers.values() {
+            match path {
+                Some(p) if p.is_up(now) => direct.push(p.endpoint),
+                _ => relay = true,
+            }
+        }
+        (direct, relay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths() {
+        let a: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let b: SocketAddr = "2.2.2.2:2".parse().unwrap();
+        let b_other: SocketAddr = "2.2.2.2:3".parse().unwrap();
+        let now = Instant::now();
+        let mut paths = Paths::default();
+        assert_eq!(paths.routes(now), (vec![], true));
+        paths.update(&[
+            PeerEndpoint::new(1, Some(a)),
+            PeerEndpoint::new(2, Some(b)),
+            PeerEndpoint::new(3, None),
+        ]);
+        assert_eq!(paths.routes(now), (vec![], true));
+        // we hear peer 1, it doesn't hear us yet
+        assert!(paths.punched(1, a, false, now));
+        assert_eq!(paths.up(now), Vec::<u32>::new());
+        assert!(paths.endpoints(now).contains(&(a, true)));
+        assert!(paths.endpoints(now).contains(&(b, false)));
+        assert!(paths.punched(1, a, true, now));
+        assert!(paths.punched(2, b, true, now));
+        // only the announced endpoint is accepted
+        assert!(!paths.punched(2, b_other, true, now));
+        assert!(!paths.punched(3, a, true, now));
+        assert_eq!(paths.received(b, now), Some(2));
+        assert_eq!(paths.received(b_other, now), None);
+        assert_eq!(paths.up(now), vec![1, 2]);
+        // peer 3 is still relayed
+        assert!(paths.routes(now).1);
+        paths.update(&[PeerEndpoint::new(1, Some(a)), PeerEndpoint::new(2, Some(b))]);
+        let (mut direct, relay) = paths.routes(now);
+        direct.sort();
+        assert_eq!((direct, relay), (vec![a, b], false));
+        // data frames keep receiving up, not the peer hearing us
+        let soon = now + DIRECT_TIMEOUT;
+        let later = soon + Duration::from_secs(1);
+        assert_eq!(paths.received(a, soon), Some(1));
+        assert_eq!(paths.routes(later), (vec![], true));
+        assert!(paths.endpoints(later).contains(&(a, true)));
+    }
+}
@@ -0,0 +1,213 @@
+//! Fragmentation of frames larger than the path MTU.
+//!
+//! A frame that doesn't fit in one datagram is serialized and split into
+//! `FrameBody::Fragment`s sharing an id, which the receiver puts back together.
+//! A frame is dropped if its fragments don't all arrive within
+//! `REASSEMBLY_TIMEOUT`. The server reassembles frames before relaying them,
+//! and fragments them again for each peer.
+use super::protocol::{Frame, FrameBody, FRAGMENT_LEN};
+use crate::metrics::{self, Component};
+use anyhow::Result;
+use deku::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Default datagram size, fits PPPoE and most tunnels
+pub const DEFAULT_MTU: usize = 1400;
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Largest frame, its length is a u16
+pub const MAX_FRAME_LEN: usize = 3 + u16::MAX as usize;
+/// Frames of a sender reassembled at once, its fragments of other frames are
+/// dropped meanwhile
+const MAX_PARTIALS: usize = 64;
+
+/// Serialized `frame`, as is if it fits in `max_len` bytes, otherwise as
+/// fragments with the id returned by `next_id`
+pub fn fragment(
+    component: Component,
+    frame: Vec<u8>,
+    max_len: usize,
+    peer_id: u32,
+    next_id: impl FnOnce() -> u32,
+) -> Result<Vec<Vec<u8>>> {
+    if frame.len() <= max_len {
+        return Ok(vec![frame]);
+    }
+    metrics::inc(&metrics::FRAGMENTED, component);
+    // a small MTU can't be honored for the largest frames, the count is a u8
+    let chunk = max_len
+        .saturating_sub(3 + FRAGMENT_LEN)
+        .max(MAX_FRAME_LEN / u8::MAX as usize + 1);
+    let count = frame.len().div_ceil(chunk) as u8;
+    let id = next_id();
+    frame
+        .chunks(chunk)
+        .enumerate()
+        .map(|(index, data)| {
+            let body = FrameBody::Fragment {
+                peer_id,
+                id,
+                index: index as u8,
+                count,
+                data: data.to_vec(),
+            };
+            Ok(Frame::try_from(body)?.to_bytes()?)
+        })
+        .collect()
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Fragments being put back together, by sender `K` and fragment id
+pub struct Reassembler<K> {
+    partials: HashMap<K, HashMap<u32, Partial>>,
+    component: Component,
+}
+
+impl<K: Hash + Eq + Copy> Reassembler<K> {
+    pub fn new(component: Component) -> Reassembler<K> {
+        Reassembler {
+            partials: HashMap::new(),
+            component,
+        }
+    }
+    /// Adds a fragment, returns the serialized frame once all its fragments arrived
+    pub fn add(
+        &mut self,
+        from: K,
+        id: u32,
+        index: u8,
+        count: u8,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let component = self.component;
+        self.partials.retain(|_, partials| {
+            partials.retain(|_, p| {
+                let alive = now.duration_since(p.started) < REASSEMBLY_TIMEOUT;
+                if !alive {
+                    metrics::inc(&metrics::REASSEMBLY_TIMEOUTS, component);
+                }
+                alive
+            });
+            !partials.is_empty()
+        });
+        if index >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(data);
+        }
+        let partials = self.partials.entry(from).or_default();
+        if !partials.contains_key(&id) && partials.len() >= MAX_PARTIALS {
+            log::debug!("Too many frames being reassembled, dropped a fragment");
+            return None;
+        }
+        let partial = partials.entry(id).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            received: 0,
+            size: 0,
+            started: now,
+        });
+        if partial.fragments.len() != count as usize {
+            return None;
+        }
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            partial.received += 1;
+            partial.size += data.len();
+            *slot = Some(data);
+        }
+        if partial.size > MAX_FRAME_LEN {
+            partials.remove(&id);
+            return None;
+        }
+        if partial.received < count as usize {
+            return None;
+        }
+        let partial = partials.remove(&id)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_fragment() {
+        let frame: Frame = FrameBody::Data {
+            peer_id: 1,
+            seq: 0,
+            channel: 6,
+            radio: None,
+            data: (0..3000).map(|i| i as u8).collect(),
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(
+            fragment(Component::Client, bytes.clone(), 4000, 1, || 0)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let datagrams = fragment(Component::Client, bytes.clone(), 1400, 1, || 7).unwrap();
+        assert_eq!(datagrams.len(), 3);
+        assert!(datagrams.iter().all(|d| d.len() <= 1400));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Component::Client);
+        let mut reassembled = None;
+        // out of order, and one twice
+        for d in [&datagrams[2], &datagrams[1], &datagrams[1], &datagrams[0]] {
+            let (_, frame) = Frame::from_bytes((d, 0)).unwrap();
+            if let FrameBody::Fragment {
+                peer_id,
+                id,
+                index,
+                count,
+                data,
+            } = frame.body
+            {
+                assert_eq!((peer_id, id, count), (1, 7, 3));
+                if let Some(r) = reassembler.add(peer_id, id, index, count, data, now) {
+                    assert!(reassembled.is_none());
+                    reassembled = Some(r);
+                }
+            }
+        }
+        assert_eq!(reassembled.unwrap(), bytes);
+
+        // the last fragment came too late
+        let (_, first) = Frame::from_bytes((&datagrams[0], 0)).unwrap();
+        if let FrameBody::Fragment { data, .. } = first.body {
+            assert_eq!(reassembler.add(1, 8, 0, 2, data.clone(), now), None);
+            let later = now + REASSEMBLY_TIMEOUT;
+            assert_eq!(reassembler.add(1, 8, 1, 2, data, later), None);
+        }
+
+        // other senders still have room when one has too many frames pending
+        let mut reassembler = Reassembler::new(Component::Client);
+        for id in 0..MAX_PARTIALS as u32 {
+            assert_eq!(reassembler.add(1, id, 0, 2, vec![1], now), None);
+        }
+        assert_eq!(reassembler.add(1, 100, 0, 2, vec![1], now), None);
+        assert_eq!(reassembler.add(1, 100, 1, 2, vec![2], now), None);
+        assert_eq!(reassembler.add(2, 100, 0, 2, vec![1], now), None);
+        assert_eq!(
+            reassembler.add(2, 100, 1, 2, vec![2], now),
+            Some(vec![1, 2])
+        );
+    }
+}
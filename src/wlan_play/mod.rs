@@ -1,3 +1,11 @@
+pub mod auth;
 pub mod client;
+pub mod compress;
+pub mod crypto;
+pub mod direct;
+pub mod fragment;
 pub mod protocol;
+pub mod radio;
+pub mod seq;
 pub mod server;
+pub mod transport;
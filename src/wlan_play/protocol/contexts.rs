@@ -0,0 +1,335 @@
+//! Header compression of relayed 802.11 frames.
+//!
+//! Most frames of a session repeat a few combinations of frame control and
+//! addresses. The sender of a hop gives each one a context id, and sends
+//! `FrameBody::ContextData` with the id instead of those 20 bytes. The first
+//! `DEFINE_REPEAT` frames of a context carry the whole header, so the receiver
+//! learns it even if some are lost. A receiver that doesn't know a context, or
+//! knows an older generation of it, drops the frame and asks for
+//! `FrameBody::ContextResync`, after which the context is defined again.
+use super::FrameBody;
+use crate::metrics::{self, Component};
+use crate::utils::ieee80211::{Frame, FrameControl, Mac};
+use deku::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Set in the context id of a frame defining the context
+pub const DEFINE: u8 = 0x80;
+/// Contexts of a sender, the least recently used one is replaced
+const MAX_CONTEXTS: usize = 64;
+/// Frames carrying the whole header after a context is (re)defined
+const DEFINE_REPEAT: u8 = 3;
+/// A receiver asks again for a context it still doesn't know after this long
+const RESYNC_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the frames of a context share
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Header {
+    frame_control: FrameControl,
+    addr1: Mac,
+    addr2: Mac,
+    addr3: Mac,
+}
+
+impl Header {
+    /// The header of a frame with three addresses, and the rest of the frame
+    /// in the form of a reference to it: duration, sequence control and body
+    fn parse(data: &[u8]) -> Option<(Header, Vec<u8>)> {
+        let ((rest, _), frame) = Frame::from_bytes((data, 0)).ok()?;
+        let sequence_control = frame.sequence_control?;
+        let header = Header {
+            frame_control: frame.frame_control,
+            addr1: frame.addr1,
+            addr2: frame.addr2?,
+            addr3: frame.addr3?,
+        };
+        let mut packed = Vec::with_capacity(4 + rest.len());
+        packed.extend_from_slice(&frame.duration_id.to_le_bytes());
+        packed.extend_from_slice(&sequence_control.to_le_bytes());
+        packed.extend_from_slice(rest);
+        Some((header, packed))
+    }
+    /// The frame `parse` was given
+    fn expand(&self, packed: &[u8]) -> Option<Vec<u8>> {
+        if packed.len() < 4 {
+            return None;
+        }
+        let frame = Frame {
+            frame_control: self.frame_control.clone(),
+            duration_id: u16::from_le_bytes([packed[0], packed[1]]),
+            addr1: self.addr1.clone(),
+            addr2: Some(self.addr2.clone()),
+            addr3: Some(self.addr3.clone()),
+            sequence_control: Some(u16::from_le_bytes([packed[2], packed[3]])),
+        };
+        let mut data = frame.to_bytes().ok()?;
+        data.extend_from_slice(&packed[4..]);
+        Some(data)
+    }
+}
+
+struct SentContext {
+    header: Header,
+    generation: u8,
+    /// frames left carrying the whole header
+    defines: u8,
+    used: u64,
+}
+
+/// Contexts of the frames sent over one hop
+pub struct Encoder {
+    contexts: Vec<SentContext>,
+    ids: HashMap<Header, u8>,
+    clock: u64,
+    component: Component,
+}
+
+impl Encoder {
+    pub fn new(component: Component) -> Encoder {
+        Encoder {
+            contexts: vec![],
+            ids: HashMap::new(),
+            clock: 0,
+            component,
+        }
+    }
+    /// `ContextData` for a `Data` frame of a frame with three addresses,
+    /// anything else as is
+    pub fn encode(&mut self, body: FrameBody) -> FrameBody {
+        let (peer_id, seq, channel, radio, data) = match body {
+            FrameBody::Data {
+                peer_id,
+                seq,
+                channel,
+                radio,
+                data,
+            } => (peer_id, seq, channel, radio, data),
+            body => return body,
+        };
+        let (header, packed) = match Header::parse(&data) {
+            Some(parsed) => parsed,
+            None => {
+                return FrameBody::Data {
+                    peer_id,
+                    seq,
+                    channel,
+                    radio,
+                    data,
+                }
+            }
+        };
+        self.clock += 1;
+        let id = match self.ids.get(&header) {
+            Some(id) => *id,
+            None => self.insert(header),
+        };
+        let context = &mut self.contexts[id as usize];
+        context.used = self.clock;
+        let (id, data) = if context.defines > 0 {
+            context.defines -= 1;
+            (id | DEFINE, data)
+        } else {
+            (id, packed)
+        };
+        FrameBody::ContextData {
+            peer_id,
+            seq,
+            channel,
+            radio,
+            context: id,
+            generation: context.generation,
+            data,
+        }
+    }
+    /// The receiver asked for `context`, define it again
+    pub fn resync(&mut self, context: u8) {
+        if let Some(context) = self.contexts.get_mut((context & !DEFINE) as usize) {
+            context.defines = DEFINE_REPEAT;
+            metrics::inc(&metrics::CONTEXT_RESYNCS, self.component);
+        }
+    }
+    fn insert(&mut self, header: Header) -> u8 {
+        let id = if self.contexts.len() < MAX_CONTEXTS {
+            self.contexts.push(SentContext {
+                header: header.clone(),
+                generation: 0,
+                defines: DEFINE_REPEAT,
+                used: self.clock,
+            });
+            self.contexts.len() - 1
+        } else {
+            let (id, oldest) = self
+                .contexts
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, c)| c.used)
+                .expect("contexts are full");
+            self.ids.remove(&oldest.header);
+            oldest.header = header.clone();
+            oldest.generation = oldest.generation.wrapping_add(1);
+            oldest.defines = DEFINE_REPEAT;
+            id
+        };
+        self.ids.insert(header, id as u8);
+        id as u8
+    }
+}
+
+pub enum Decoded {
+    Data(FrameBody),
+    /// The context is unknown, ask the sender to define it again
+    Resync(u8),
+    Dropped,
+}
+
+/// Contexts of the frames received over one hop
+#[derive(Default)]
+pub struct Decoder {
+    contexts: HashMap<u8, (u8, Header)>,
+    /// when each unknown context was asked for
+    asked: HashMap<u8, Instant>,
+}
+
+impl Decoder {
+    /// `Data` for a `ContextData` frame, anything else as is
+    pub fn decode(&mut self, body: FrameBody, now: Instant) -> Decoded {
+        let (peer_id, seq, channel, radio, context, generation, data) = match body {
+            FrameBody::ContextData {
+                peer_id,
+                seq,
+                channel,
+                radio,
+                context,
+                generation,
+                data,
+            } => (peer_id, seq, channel, radio, context, generation, data),
+            body => return Decoded::Data(body),
+        };
+        let data_body = |data: Vec<u8>| FrameBody::Data {
+            peer_id,
+            seq,
+            channel,
+            radio,
+            data,
+        };
+        let id = context & !DEFINE;
+        if context & DEFINE != 0 {
+            return match Header::parse(&data) {
+                Some((header, _)) => {
+                    self.contexts.insert(id, (generation, header));
+                    self.asked.remove(&id);
+                    Decoded::Data(data_body(data))
+                }
+                None => Decoded::Dropped,
+            };
+        }
+        match self.contexts.get(&id) {
+            Some((g, header)) if *g == generation => match header.expand(&data) {
+                Some(data) => Decoded::Data(data_body(data)),
+                None => Decoded::Dropped,
+            },
+            _ => match self.asked.get(&id) {
+                Some(asked) if now.duration_since(*asked) < RESYNC_INTERVAL => Decoded::Dropped,
+                _ => {
+                    self.asked.insert(id, now);
+                    Decoded::Resync(id)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Frame as WireFrame;
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn data(data: &[u8]) -> FrameBody {
+        FrameBody::Data {
+            peer_id: 1,
+            seq: 2,
+            channel: 6,
+            radio: None,
+            data: data.to_vec(),
+        }
+    }
+
+    fn decoded(decoded: Decoded) -> FrameBody {
+        match decoded {
+            Decoded::Data(body) => body,
+            _ => panic!("not decoded"),
+        }
+    }
+
+    #[test]
+    fn test_contexts() {
+        let frame = [
+            0x88, 0x41, 0x3a, 0x00, 0x2c, 0xf8, 0x9b, 0xdd, 0x06, 0xa0, 0x00, 0x20, 0xa6, 0xfc,
+            0xb0, 0x36, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x20, 0x00, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x20, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let now = Instant::now();
+        let mut encoder = Encoder::new(Component::Client);
+        let mut decoder = Decoder::default();
+        for _ in 0..DEFINE_REPEAT {
+            let body = encoder.encode(data(&frame));
+            assert!(matches!(body, FrameBody::ContextData { context, .. } if context == DEFINE));
+            assert_eq!(decoded(decoder.decode(body, now)), data(&frame));
+        }
+        let body = encoder.encode(data(&frame));
+        let bytes = WireFrame::try_from(body.clone())
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let plain = WireFrame::try_from(data(&frame))
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        assert_eq!(bytes.len(), plain.len() - 20 + 2);
+        let (_, parsed) = WireFrame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed.body, body);
+        assert_eq!(decoded(decoder.decode(body, now)), data(&frame));
+
+        // the definitions were lost
+        let mut decoder = Decoder::default();
+        let body = encoder.encode(data(&frame));
+        assert!(matches!(
+            decoder.decode(body.clone(), now),
+            Decoded::Resync(0)
+        ));
+        assert!(matches!(
+            decoder.decode(body.clone(), now),
+            Decoded::Dropped
+        ));
+        let later = now + RESYNC_INTERVAL;
+        assert!(matches!(decoder.decode(body, later), Decoded::Resync(0)));
+        encoder.resync(0);
+        let body = encoder.encode(data(&frame));
+        assert_eq!(decoded(decoder.decode(body, later)), data(&frame));
+        let body = encoder.encode(data(&frame));
+        assert_eq!(decoded(decoder.decode(body, later)), data(&frame));
+
+        // the context is replaced by another generation
+        let mut other = frame;
+        for i in 0..MAX_CONTEXTS {
+            other[9] = i as u8 + 1;
+            encoder.encode(data(&other));
+        }
+        let body = encoder.encode(data(&other));
+        assert!(matches!(body, FrameBody::ContextData { generation: 1, .. }));
+        let mut stale = body.clone();
+        if let FrameBody::ContextData { context, .. } = &mut stale {
+            *context &= !DEFINE;
+        }
+        assert!(matches!(decoder.decode(stale, later), Decoded::Resync(0)));
+        assert_eq!(decoded(decoder.decode(body, later)), data(&other));
+
+        // control frames and other bodies aren't touched
+        let ack = [0xd4, 0x00, 0x00, 0x00, 0x2c, 0xf8, 0x9b, 0xdd, 0x06, 0xa0];
+        assert_eq!(encoder.encode(data(&ack)), data(&ack));
+        assert_eq!(encoder.encode(FrameBody::Keepalive), FrameBody::Keepalive);
+    }
+}
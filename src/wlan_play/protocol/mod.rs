@@ -0,0 +1,748 @@
+use crate::agent::{RadioInfo, RADIO_INFO_LEN};
+use crate::config::Mode;
+use crate::utils::ieee80211::Mac;
+use anyhow::{anyhow, Result};
+use deku::bitvec::{BitSlice, BitVec, Msb0};
+use deku::ctx::Limit;
+use deku::prelude::*;
+use std::convert::TryFrom;
+use std::mem::size_of_val;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+pub mod contexts;
+
+/// Written in every `Frame`, peers with another version are rejected
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Software version sent in `Hello` and `Welcome`
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How often clients ping the server and their peers, pings keep them alive
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often hosts publish the sessions they see, the server forgets them
+/// after three intervals
+pub const SESSION_INTERVAL: Duration = Duration::from_secs(5);
+/// `FrameBody::ListSessions` is padded to this length, the answer of the
+/// server is no larger than a few times the request
+pub const LIST_SESSIONS_LEN: usize = 600;
+/// Longest room name in bytes, its length is a u8 in some frames
+pub const MAX_ROOM_LEN: usize = u8::MAX as usize;
+/// Nonce length of `FrameBody::Encrypted`
+pub const NONCE_LEN: usize = 24;
+/// Fixed part of `FrameBody::Hello`
+const HELLO_LEN: usize = 1 + 1 + 4 + 1;
+/// Sender and sequence number of `FrameBody::Data` and `FrameBody::Encrypted`
+const SEQ_LEN: usize = 4 + 4;
+/// Fixed part of `FrameBody::Fragment`
+pub const FRAGMENT_LEN: usize = 4 + 4 + 1 + 1;
+/// Context id and generation of `FrameBody::ContextData`
+const CONTEXT_LEN: usize = 1 + 1;
+
+/// The peer sends `FrameBody::Encrypted` instead of `FrameBody::Data`
+pub const CAP_ENCRYPTION: u32 = 1 << 0;
+/// The peer can be reached directly over UDP, after hole punching
+pub const CAP_DIRECT: u32 = 1 << 1;
+/// The peer takes `FrameBody::Batch`, the server unbatches frames for the others
+pub const CAP_BATCH: u32 = 1 << 2;
+/// The peer takes `FrameBody::Compressed` from the server
+pub const CAP_COMPRESSION: u32 = 1 << 3;
+/// The peer takes `FrameBody::ContextData` from the server
+pub const CAP_HEADER_CONTEXTS: u32 = 1 << 4;
+/// Capabilities implemented by this build
+pub const CAPABILITIES: u32 =
+    CAP_ENCRYPTION | CAP_DIRECT | CAP_BATCH | CAP_COMPRESSION | CAP_HEADER_CONTEXTS;
+
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone, Copy)]
+#[deku(type = "u8")]
+pub enum ErrorCode {
+    /// Protocol version mismatch, the peer should upgrade
+    #[deku(id = "1")]
+    Incompatible,
+    /// The server doesn't know the peer, it should send `Hello` again
+    #[deku(id = "2")]
+    NotJoined,
+    /// Removed by the server admin
+    #[deku(id = "3")]
+    Kicked,
+    #[deku(id = "4")]
+    Banned,
+    /// The server is full, or the room is full or not allowed
+    #[deku(id = "5")]
+    Refused,
+}
+
+/// A peer of the room, and its public UDP endpoint if it can be reached directly
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct PeerEndpoint {
+    pub peer_id: u32,
+    /// 4 or 6, 0 without endpoint
+    family: u8,
+    #[deku(count = "match *family { 4 => 4, 6 => 16, _ => 0 }")]
+    ip: Vec<u8>,
+    port: u16,
+}
+
+impl PeerEndpoint {
+    pub fn new(peer_id: u32, endpoint: Option<SocketAddr>) -> PeerEndpoint {
+        let (family, ip) = match endpoint.map(|e| e.ip()) {
+            Some(IpAddr::V4(ip)) => (4, ip.octets().to_vec()),
+            Some(IpAddr::V6(ip)) => (6, ip.octets().to_vec()),
+            None => (0, vec![]),
+        };
+        PeerEndpoint {
+            peer_id,
+            family,
+            ip,
+            port: endpoint.map_or(0, |e| e.port()),
+        }
+    }
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        let ip: IpAddr = match self.family {
+            4 => <[u8; 4]>::try_from(&self.ip[..]).ok()?.into(),
+            6 => <[u8; 16]>::try_from(&self.ip[..]).ok()?.into(),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, self.port))
+    }
+    fn len(&self) -> usize {
+        4 + 1 + self.ip.len() + 2
+    }
+}
+
+/// A local wireless session of a Switch, decoded from its action frames
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct SessionInfo {
+    pub session_id: [u8; 16],
+    pub channel: u32,
+    /// transmitter of the action frames
+    pub host: Mac,
+    /// title id of the game, usually
+    pub local_communication_id: u64,
+    pub scene_id: u16,
+}
+
+/// A session and the peer hosting it
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct SessionEntry {
+    pub peer_id: u32,
+    room_len: u8,
+    #[deku(count = "room_len")]
+    room: Vec<u8>,
+    pub session: SessionInfo,
+}
+
+impl SessionEntry {
+    pub fn new(peer_id: u32, room: &str, session: SessionInfo) -> Result<SessionEntry> {
+        Ok(SessionEntry {
+            peer_id,
+            room_len: room_len(room)?,
+            room: room.as_bytes().to_vec(),
+            session,
+        })
+    }
+    pub fn room(&self) -> String {
+        String::from_utf8_lossy(&self.room).into_owned()
+    }
+    /// Bytes on the wire
+    pub fn size(&self) -> usize {
+        4 + 1 + self.room.len() + SESSION_LEN
+    }
+}
+
+const SESSION_LEN: usize = 16 + 4 + 6 + 8 + 2;
+
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct Frame {
+    #[deku(bits = 3)]
+    pub version: u8,
+    #[deku(bits = 5)]
+    pub frame_type: u8,
+    pub len: u16,
+    #[deku(ctx = "*frame_type, *len")]
+    pub body: FrameBody,
+}
+
+impl Frame {
+    /// Bytes on the wire
+    pub fn size(&self) -> usize {
+        3 + self.len as usize
+    }
+}
+
+#[derive(DekuRead, DekuWrite, Eq, PartialEq, Hash, Debug, Clone)]
+#[deku(ctx = "id: u8, len: u16", id = "id")]
+pub enum FrameBody {
+    #[deku(id = "0")]
+    Keepalive,
+    /// An 802.11 frame. `seq` counts the frames of the sender `peer_id`.
+    #[deku(id = "1")]
+    Data {
+        peer_id: u32,
+        seq: u32,
+        channel: u32,
+        /// what the radio of the sender reported, behind a presence byte
+        #[deku(
+            reader = "read_radio(deku::rest)",
+            writer = "write_radio(deku::output, radio)"
+        )]
+        radio: Option<RadioInfo>,
+        #[deku(count = "body_len(len, SEQ_LEN + size_of_val(channel) + radio_len(radio))?")]
+        data: Vec<u8>,
+    },
+    /// Sent by clients to join a room, frames are only relayed between
+    /// peers in the same room. The server answers `Welcome` or `Error`.
+    #[deku(id = "2")]
+    Hello {
+        version: u8,
+        role: Mode,
+        capabilities: u32,
+        /// behind its length byte
+        #[deku(
+            reader = "read_prefixed(deku::rest)",
+            writer = "write_prefixed(deku::output, software)"
+        )]
+        software: Vec<u8>,
+        #[deku(count = "body_len(len, HELLO_LEN + software.len())?")]
+        room: Vec<u8>,
+    },
+    /// Another frame encrypted end-to-end, relayed like `Data`
+    #[deku(id = "3")]
+    Encrypted {
+        /// copied from the inner `Data`, so the server can count the frames
+        peer_id: u32,
+        seq: u32,
+        nonce: [u8; NONCE_LEN],
+        #[deku(count = "body_len(len, SEQ_LEN + NONCE_LEN)?")]
+        data: Vec<u8>,
+    },
+    #[deku(id = "4")]
+    Welcome {
+        peer_id: u32,
+        /// capabilities of the server
+        capabilities: u32,
+        #[deku(count = "body_len(len, size_of_val(peer_id) + size_of_val(capabilities))?")]
+        software: Vec<u8>,
+    },
+    #[deku(id = "5")]
+    Error {
+        code: ErrorCode,
+        #[deku(count = "body_len(len, 1)?")]
+        message: Vec<u8>,
+    },
+    /// The other peers of the room, sent by the server to peers with
+    /// `CAP_DIRECT` whenever the room changes
+    #[deku(id = "6")]
+    Peers {
+        count: u16,
+        #[deku(count = "count")]
+        peers: Vec<PeerEndpoint>,
+    },
+    /// Sent between peers to open and keep a direct path
+    #[deku(id = "7")]
+    Punch {
+        peer_id: u32,
+        /// 1 if the sender hears the punches of the receiver
+        heard: u8,
+    },
+    /// Peers the sender reaches directly, the server doesn't relay to them
+    #[deku(id = "8")]
+    Direct {
+        #[deku(count = "len as usize / 4")]
+        peer_ids: Vec<u32>,
+    },
+    /// A session seen by a host, sent every `SESSION_INTERVAL` while it lasts
+    #[deku(id = "9")]
+    Session { session: SessionInfo },
+    /// Asks the server for the sessions of a room, or of all rooms. Doesn't
+    /// need `Hello`.
+    #[deku(id = "10")]
+    ListSessions {
+        all_rooms: u8,
+        room_len: u8,
+        #[deku(count = "room_len")]
+        room: Vec<u8>,
+        #[deku(count = "body_len(len, 2 + *room_len as usize)?")]
+        padding: Vec<u8>,
+    },
+    #[deku(id = "11")]
+    Sessions {
+        count: u16,
+        #[deku(count = "count")]
+        sessions: Vec<SessionEntry>,
+    },
+    /// The sender is shutting down, the server drops a peer saying it and
+    /// says it to all peers before stopping
+    #[deku(id = "12")]
+    Goodbye {
+        #[deku(count = "len")]
+        reason: Vec<u8>,
+    },
+    /// Asks `to` for a `Pong`, through the server. The server answers itself
+    /// if `to` is 0. `timestamp` is only meaningful to the sender.
+    #[deku(id = "13")]
+    Ping { from: u32, to: u32, timestamp: u64 },
+    /// Answer to `Ping`, with the same `timestamp`
+    #[deku(id = "14")]
+    Pong { from: u32, to: u32, timestamp: u64 },
+    /// `Data`, `Encrypted`, `Compressed` or `ContextData` frames of one sender,
+    /// in one datagram
+    #[deku(id = "15")]
+    Batch {
+        count: u16,
+        #[deku(reader = "read_batch(deku::rest, *count)")]
+        frames: Vec<Frame>,
+    },
+    /// Piece `index` of `count` of a serialized frame too large for one
+    /// datagram. `peer_id` is the sender, 0 for the server.
+    #[deku(id = "16")]
+    Fragment {
+        peer_id: u32,
+        id: u32,
+        index: u8,
+        count: u8,
+        #[deku(count = "body_len(len, FRAGMENT_LEN)?")]
+        data: Vec<u8>,
+    },
+    /// A serialized `Data` or `ContextData` frame compressed by
+    /// `compress::Compressor`, between a peer and the server if both have
+    /// `CAP_COMPRESSION`
+    #[deku(id = "17")]
+    Compressed {
+        #[deku(count = "len")]
+        data: Vec<u8>,
+    },
+    /// `Data` with the 802.11 addresses replaced by a context of
+    /// `contexts::Encoder`, between a peer and the server if both have
+    /// `CAP_HEADER_CONTEXTS`
+    #[deku(id = "18")]
+    ContextData {
+        peer_id: u32,
+        seq: u32,
+        channel: u32,
+        #[deku(
+            reader = "read_radio(deku::rest)",
+            writer = "write_radio(deku::output, radio)"
+        )]
+        radio: Option<RadioInfo>,
+        /// with `contexts::DEFINE` set, `data` is the whole 802.11 frame
+        context: u8,
+        generation: u8,
+        #[deku(
+            count = "body_len(len, SEQ_LEN + size_of_val(channel) + radio_len(radio) + CONTEXT_LEN)?"
+        )]
+        data: Vec<u8>,
+    },
+    /// Asks the other end to define `context` again, it was unknown or of
+    /// another generation
+    #[deku(id = "19")]
+    ContextResync { context: u8 },
+}
+
+impl FrameBody {
+    pub fn hello(role: Mode, room: &str, capabilities: u32) -> FrameBody {
+        FrameBody::Hello {
+            version: PROTOCOL_VERSION,
+            role,
+            capabilities,
+            software: SOFTWARE_VERSION.as_bytes().to_vec(),
+            room: room.as_bytes().to_vec(),
+        }
+    }
+    /// Sessions of `room`, or of all rooms if None
+    pub fn list_sessions(room: Option<&str>) -> Result<FrameBody> {
+        let all_rooms = room.is_none() as u8;
+        let room_len = room_len(room.unwrap_or_default())?;
+        let room = room.unwrap_or_default().as_bytes().to_vec();
+        Ok(FrameBody::ListSessions {
+            all_rooms,
+            room_len,
+            padding: vec![0; LIST_SESSIONS_LEN.saturating_sub(room.len() + 2)],
+            room,
+        })
+    }
+    pub fn batch(frames: Vec<Frame>) -> FrameBody {
+        FrameBody::Batch {
+            count: frames.len() as u16,
+            frames,
+        }
+    }
+    pub fn goodbye(reason: impl Into<String>) -> FrameBody {
+        FrameBody::Goodbye {
+            reason: reason.into().into_bytes(),
+        }
+    }
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> FrameBody {
+        FrameBody::Error {
+            code,
+            message: message.into().into_bytes(),
+        }
+    }
+}
+
+fn room_len(room: &str) -> Result<u8> {
+    u8::try_from(room.len()).map_err(|_| anyhow!("Room name longer than {} bytes", MAX_ROOM_LEN))
+}
+
+/// Length of the variable part of a body of `len` bytes, after `fixed` bytes
+fn body_len(len: u16, fixed: usize) -> Result<usize, DekuError> {
+    (len as usize)
+        .checked_sub(fixed)
+        .ok_or_else(|| DekuError::Parse(format!("length {} is less than {}", len, fixed)))
+}
+
+fn read_prefixed(rest: &BitSlice<Msb0, u8>) -> Result<(&BitSlice<Msb0, u8>, Vec<u8>), DekuError> {
+    let (rest, len) = u8::read(rest, ())?;
+    Vec::<u8>::read(rest, Limit::new_count(len as usize))
+}
+
+fn write_prefixed(output: &mut BitVec<Msb0, u8>, bytes: &[u8]) -> Result<(), DekuError> {
+    u8::try_from(bytes.len())?.write(output, ())?;
+    bytes.write(output, ())
+}
+
+fn radio_len(radio: &Option<RadioInfo>) -> usize {
+    1 + radio.map_or(0, |_| RADIO_INFO_LEN)
+}
+
+/// Types of the frames a batch may hold, never another batch
+const BATCHED: &[u8] = &[1, 3, 17, 18];
+
+fn read_batch(
+    rest: &BitSlice<Msb0, u8>,
+    count: u16,
+) -> Result<(&BitSlice<Msb0, u8>, Vec<Frame>), DekuError> {
+    let mut rest = rest;
+    let mut frames = vec![];
+    for _ in 0..count {
+        // checked before parsing, nested batches would recurse without limit
+        let (_, header) = u8::read(rest, ())?;
+        let frame_type = header & 0x1f;
+        if !BATCHED.contains(&frame_type) {
+            return Err(DekuError::Parse(format!(
+                "frame type {} in a batch",
+                frame_type
+            )));
+        }
+        let (next, frame) = Frame::read(rest, ())?;
+        rest = next;
+        frames.push(frame);
+    }
+    Ok((rest, frames))
+}
+
+fn read_radio(
+    rest: &BitSlice<Msb0, u8>,
+) -> Result<(&BitSlice<Msb0, u8>, Option<RadioInfo>), DekuError> {
+    let (rest, present) = u8::read(rest, ())?;
+    if present == 0 {
+        return Ok((rest, None));
+    }
+    let (rest, radio) = RadioInfo::read(rest, ())?;
+    Ok((rest, Some(radio)))
+}
+
+fn write_radio(output: &mut BitVec<Msb0, u8>, radio: &Option<RadioInfo>) -> Result<(), DekuError> {
+    match radio {
+        Some(radio) => {
+            1u8.write(output, ())?;
+            radio.write(output, ())
+        }
+        None => 0u8.write(output, ()),
+    }
+}
+
+impl TryFrom<FrameBody> for Frame {
+    type Error = anyhow::Error;
+
+    fn try_from(body: FrameBody) -> Result<Frame> {
+        let (frame_type, len) = match &body {
+            FrameBody::Keepalive => (0u8, 0),
+            FrameBody::Data {
+                data,
+                channel,
+                radio,
+                ..
+            } => (
+                1,
+                SEQ_LEN + size_of_val(channel) + radio_len(radio) + data.len(),
+            ),
+            FrameBody::Hello { software, room, .. } => (2, HELLO_LEN + software.len() + room.len()),
+            FrameBody::Encrypted { data, .. } => (3, SEQ_LEN + NONCE_LEN + data.len()),
+            FrameBody::Welcome { software, .. } => (4, 8 + software.len()),
+            FrameBody::Error { message, .. } => (5, 1 + message.len()),
+            FrameBody::Peers { peers, .. } => (6, 2 + peers.iter().map(|p| p.len()).sum::<usize>()),
+            FrameBody::Punch { .. } => (7, 5),
+            FrameBody::Direct { peer_ids } => (8, 4 * peer_ids.len()),
+            FrameBody::Session { .. } => (9, SESSION_LEN),
+            FrameBody::ListSessions { room, padding, .. } => (10, 2 + room.len() + padding.len()),
+            FrameBody::Sessions { sessions, .. } => {
+                (11, 2 + sessions.iter().map(|s| s.size()).sum::<usize>())
+            }
+            FrameBody::Goodbye { reason } => (12, reason.len()),
+            FrameBody::Ping { .. } => (13, 16),
+            FrameBody::Pong { .. } => (14, 16),
+            FrameBody::Batch { frames, .. } => {
+                (15, 2 + frames.iter().map(|f| f.size()).sum::<usize>())
+            }
+            FrameBody::Fragment { data, .. } => (16, FRAGMENT_LEN + data.len()),
+            FrameBody::Compressed { data } => (17, data.len()),
+            FrameBody::ContextData {
+                data,
+                channel,
+                radio,
+                ..
+            } => (
+                18,
+                SEQ_LEN + size_of_val(channel) + radio_len(radio) + CONTEXT_LEN + data.len(),
+            ),
+            FrameBody::ContextResync { .. } => (19, 1),
+        };
+        let len =
+            u16::try_from(len).map_err(|_| anyhow!("Frame body of {} bytes is too long", len))?;
+        Ok(Frame {
+            version: PROTOCOL_VERSION,
+            frame_type,
+            len,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_hello() {
+        let frame: Frame = FrameBody::Hello {
+            version: 1,
+            role: Mode::Station,
+            capabilities: 3,
+            software: b"0.1".to_vec(),
+            room: b"room".to_vec(),
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            &[0x62u8, 14, 0, 1, 1, 3, 0, 0, 0, 3, b'0', b'.', b'1', b'r', b'o', b'o', b'm']
+        );
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+    }
+
+    #[test]
+    fn test_error() {
+        let frame: Frame = FrameBody::error(ErrorCode::NotJoined, "who")
+            .try_into()
+            .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes, &[0x65u8, 4, 0, 2, b'w', b'h', b'o']);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+    }
+
+    #[test]
+    fn test_data() {
+        let frame: Frame = FrameBody::Data {
+            peer_id: 1,
+            seq: 2,
+            channel: 6,
+            radio: None,
+            data: vec![0xd4, 0],
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            &[0x61u8, 15, 0, 1, 0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 0, 0xd4, 0]
+        );
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+
+        let frame: Frame = FrameBody::Data {
+            peer_id: 1,
+            seq: 3,
+            channel: 6,
+            radio: Some(RadioInfo {
+                tsft: 1,
+                signal: -40,
+                noise: -95,
+                freq: 2437,
+                rate: 1_000_000,
+                antenna: 1,
+            }),
+            data: vec![0xd4, 0],
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + 12 + 1 + RADIO_INFO_LEN + 2);
+        assert_eq!(&bytes[15..19], &[1, 1, 0, 0]);
+        assert_eq!(&bytes[bytes.len() - 2..], &[0xd4, 0]);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+
+        let frame: Frame = FrameBody::Ping {
+            from: 1,
+            to: 0,
+            timestamp: 3,
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + 16);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+    }
+
+    /// A header with length `len` and more bytes than it says
+    fn short(frame_type: u8, len: u16) -> Vec<u8> {
+        let mut bytes = vec![PROTOCOL_VERSION << 5 | frame_type];
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&[1; 64]);
+        bytes
+    }
+
+    #[test]
+    fn test_short_len() {
+        // less than the fixed part of the body
+        for frame_type in &[1, 2, 3, 4, 5, 10, 16, 18] {
+            match Frame::from_bytes((&short(*frame_type, 0), 0)) {
+                Err(DekuError::Parse(e)) => assert!(e.starts_with("length"), "{}", e),
+                r => panic!("type {}: {:?}", frame_type, r),
+            }
+        }
+        // radio metadata that the length doesn't cover
+        for (frame_type, len) in &[(1, SEQ_LEN + 4 + 1), (18, SEQ_LEN + 4 + 1 + CONTEXT_LEN)] {
+            match Frame::from_bytes((&short(*frame_type, *len as u16), 0)) {
+                Err(DekuError::Parse(e)) => assert!(e.starts_with("length"), "{}", e),
+                r => panic!("type {} with radio: {:?}", frame_type, r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_body() {
+        let data = vec![0; u16::MAX as usize];
+        assert!(Frame::try_from(FrameBody::Compressed { data: data.clone() }).is_ok());
+        let body = FrameBody::Fragment {
+            peer_id: 1,
+            id: 2,
+            index: 0,
+            count: 1,
+            data,
+        };
+        assert!(Frame::try_from(body).is_err());
+    }
+
+    #[test]
+    fn test_batch() {
+        let frames: Vec<Frame> = (0..3)
+            .map(|seq| {
+                FrameBody::Data {
+                    peer_id: 1,
+                    seq,
+                    channel: 6,
+                    radio: None,
+                    data: vec![0xd4; seq as usize],
+                }
+                .try_into()
+                .unwrap()
+            })
+            .collect();
+        let frame: Frame = FrameBody::batch(frames.clone()).try_into().unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), frame.size());
+        assert_eq!(
+            bytes.len(),
+            3 + 2 + frames.iter().map(|f| f.size()).sum::<usize>()
+        );
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed.body, FrameBody::batch(frames));
+
+        // deeply nested batches, which would overflow the stack
+        let mut bytes = vec![];
+        for _ in 0..20_000 {
+            bytes.extend_from_slice(&[PROTOCOL_VERSION << 5 | 15, 2, 0, 1, 0]);
+        }
+        assert!(Frame::from_bytes((&bytes, 0)).is_err());
+        let nested: Frame = FrameBody::batch(vec![frame]).try_into().unwrap();
+        let bytes = nested.to_bytes().unwrap();
+        match Frame::from_bytes((&bytes, 0)) {
+            Err(DekuError::Parse(e)) => assert_eq!(e, "frame type 15 in a batch"),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_peers() {
+        let v4: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6".parse().unwrap();
+        let peers = vec![
+            PeerEndpoint::new(1, Some(v4)),
+            PeerEndpoint::new(2, Some(v6)),
+            PeerEndpoint::new(3, None),
+        ];
+        let frame: Frame = FrameBody::Peers {
+            count: peers.len() as u16,
+            peers,
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + frame.len as usize);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+        if let FrameBody::Peers { peers, .. } = parsed.body {
+            assert_eq!(peers[0].endpoint(), Some(v4));
+            assert_eq!(peers[1].endpoint(), Some(v6));
+            assert_eq!(peers[2].endpoint(), None);
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let frame: Frame = FrameBody::list_sessions(Some("room"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + LIST_SESSIONS_LEN);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+        let long = "x".repeat(MAX_ROOM_LEN + 1);
+        assert!(FrameBody::list_sessions(Some(&long)).is_err());
+
+        let (_, host) = Mac::from_bytes((&[2, 0, 0, 0, 0, 1], 0)).unwrap();
+        let session = SessionInfo {
+            session_id: [7; 16],
+            channel: 6,
+            host,
+            local_communication_id: 0x0100_0000_0000_1000,
+            scene_id: 1,
+        };
+        let sessions = vec![
+            SessionEntry::new(1, "room", session.clone()).unwrap(),
+            SessionEntry::new(2, "", session.clone()).unwrap(),
+        ];
+        assert!(SessionEntry::new(3, &long, session.clone()).is_err());
+        let frame: Frame = FrameBody::Sessions {
+            count: sessions.len() as u16,
+            sessions,
+        }
+        .try_into()
+        .unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + frame.len as usize);
+        let (_, parsed) = Frame::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, frame);
+
+        let frame: Frame = FrameBody::Session { session }.try_into().unwrap();
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 3 + frame.len as usize);
+    }
+}
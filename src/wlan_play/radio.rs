@@ -0,0 +1,50 @@
+use crate::agent::RadioInfo;
+
+/// Weight of a new frame in the average
+const WEIGHT: f64 = 1.0 / 8.0;
+
+/// Smoothed signal strength of the frames a site captures
+#[derive(Default, Clone, Debug)]
+pub struct Signal {
+    average: Option<f64>,
+}
+
+impl Signal {
+    pub fn add(&mut self, radio: &RadioInfo) {
+        if radio.signal == 0 {
+            return;
+        }
+        let signal = radio.signal as f64;
+        self.average = Some(match self.average {
+            Some(average) => average + (signal - average) * WEIGHT,
+            None => signal,
+        });
+    }
+    /// Average in dBm, none until a frame with a known signal arrived
+    pub fn dbm(&self) -> Option<f64> {
+        self.average.map(|a| (a * 10.0).round() / 10.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal() {
+        let radio = |signal| RadioInfo {
+            signal,
+            ..Default::default()
+        };
+        let mut s = Signal::default();
+        assert_eq!(s.dbm(), None);
+        s.add(&radio(0));
+        assert_eq!(s.dbm(), None);
+        s.add(&radio(-40));
+        assert_eq!(s.dbm(), Some(-40.0));
+        s.add(&radio(-48));
+        assert_eq!(s.dbm(), Some(-41.0));
+        s.add(&radio(0));
+        assert_eq!(s.dbm(), Some(-41.0));
+    }
+}
@@ -0,0 +1,117 @@
+use serde_derive::Serialize;
+
+/// Sequence numbers further than this from the highest one seen restart the
+/// tracking, the sender probably restarted
+const RESYNC: u32 = 1 << 16;
+const WINDOW: u32 = 64;
+
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct SeqStats {
+    pub received: u64,
+    /// gaps in the sequence not filled yet
+    pub lost: u64,
+    /// frames older than one received before them
+    pub reordered: u64,
+    pub duplicates: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Seq {
+    InOrder,
+    Reordered,
+    Duplicate,
+}
+
+/// Loss, reordering and duplicates in the sequence numbers of one sender
+#[derive(Default)]
+pub struct SeqTracker {
+    /// highest sequence number seen
+    top: Option<u32>,
+    /// bit `n` is set if `top - n` was seen
+    bitmap: u64,
+    pub stats: SeqStats,
+}
+
+impl SeqTracker {
+    pub fn track(&mut self, seq: u32) -> Seq {
+        let top = match self.top {
+            Some(top) if seq.wrapping_sub(top) < RESYNC || top.wrapping_sub(seq) < RESYNC => top,
+            _ => {
+                self.top = Some(seq);
+                self.bitmap = 1;
+                self.stats.received += 1;
+                return Seq::InOrder;
+            }
+        };
+        let ahead = seq.wrapping_sub(top);
+        if ahead != 0 && ahead < RESYNC {
+            self.stats.lost += ahead as u64 - 1;
+            self.bitmap = if ahead >= WINDOW {
+                0
+            } else {
+                self.bitmap << ahead
+            };
+            self.bitmap |= 1;
+            self.top = Some(seq);
+            self.stats.received += 1;
+            return Seq::InOrder;
+        }
+        let behind = top.wrapping_sub(seq);
+        // frames older than the window can't be told from duplicates
+        if behind >= WINDOW || self.bitmap & (1 << behind) != 0 {
+            self.stats.duplicates += 1;
+            return Seq::Duplicate;
+        }
+        self.bitmap |= 1 << behind;
+        // counted as lost when the newer frame came
+        self.stats.lost = self.stats.lost.saturating_sub(1);
+        self.stats.reordered += 1;
+        self.stats.received += 1;
+        Seq::Reordered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track() {
+        let mut t = SeqTracker::default();
+        assert_eq!(t.track(1), Seq::InOrder);
+        assert_eq!(t.track(2), Seq::InOrder);
+        assert_eq!(t.track(5), Seq::InOrder);
+        assert_eq!(t.stats.lost, 2);
+        assert_eq!(t.track(4), Seq::Reordered);
+        assert_eq!(t.track(4), Seq::Duplicate);
+        assert_eq!(t.track(5), Seq::Duplicate);
+        assert_eq!(
+            t.stats,
+            SeqStats {
+                received: 4,
+                lost: 1,
+                reordered: 1,
+                duplicates: 2,
+            }
+        );
+
+        let mut t = SeqTracker::default();
+        t.track(1);
+        t.track(WINDOW + 1);
+        assert_eq!(t.track(2), Seq::Reordered);
+        assert_eq!(t.track(2), Seq::Duplicate);
+        // too old to know
+        assert_eq!(t.track(1), Seq::Duplicate);
+        assert_eq!(t.track(1), Seq::Duplicate);
+        assert_eq!(t.stats.lost, WINDOW as u64 - 2);
+
+        let mut t = SeqTracker::default();
+        t.track(u32::MAX);
+        assert_eq!(t.track(0), Seq::InOrder);
+        assert_eq!(t.stats.lost, 0);
+        // the sender restarted
+        assert_eq!(t.track(1 << 20), Seq::InOrder);
+        assert_eq!(t.track((1 << 20) + 1), Seq::InOrder);
+        assert_eq!(t.stats.lost, 0);
+    }
+}
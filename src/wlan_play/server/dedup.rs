@@ -0,0 +1,72 @@
+use crate::wlan_play::transport::PeerAddr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use tokio::time::{Duration, Instant};
+
+/// Drops copies of a frame relayed by different peers of a room, e.g. two hosts
+/// in range of the same console, arriving within `window` of the first one
+pub struct Dedup {
+    window: Duration,
+    /// frame hash to the peer it was first seen from, and when
+    seen: HashMap<u64, (PeerAddr, Instant)>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Dedup {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+    /// Whether the frame is a copy of one sent by another peer
+    pub fn is_duplicate(
+        &mut self,
+        room: &str,
+        addr: PeerAddr,
+        channel: u32,
+        data: &[u8],
+        now: Instant,
+    ) -> bool {
+        if self.window == Duration::ZERO {
+            return false;
+        }
+        let mut hasher = DefaultHasher::new();
+        (room, channel, data).hash(&mut hasher);
+        let hash = hasher.finish();
+        match self.seen.get(&hash) {
+            Some((peer, at)) if *peer != addr && now - *at <= self.window => true,
+            _ => {
+                self.seen.insert(hash, (addr, now));
+                false
+            }
+        }
+    }
+    pub fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.seen.retain(|_, (_, at)| now - *at <= window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let (a, b) = (
+            PeerAddr::Udp(([127, 0, 0, 1], 1).into(), 0),
+            PeerAddr::Udp(([127, 0, 0, 1], 2).into(), 0),
+        );
+        let now = Instant::now();
+        let later = now + Duration::from_millis(200);
+        let mut dedup = Dedup::new(Duration::from_millis(100));
+        assert!(!dedup.is_duplicate("r", a, 6, b"frame", now));
+        assert!(dedup.is_duplicate("r", b, 6, b"frame", now));
+        // the same peer may send the same bytes again, e.g. ACKs
+        assert!(!dedup.is_duplicate("r", a, 6, b"frame", now));
+        assert!(!dedup.is_duplicate("other", b, 6, b"frame", now));
+        assert!(!dedup.is_duplicate("r", b, 1, b"frame", now));
+        assert!(!dedup.is_duplicate("r", b, 6, b"frame", later));
+    }
+}
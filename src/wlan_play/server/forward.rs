@@ -0,0 +1,112 @@
+use crate::config::{Mode, MultiHost, Routing, RoutingConfig};
+use crate::utils::ieee80211::Mac;
+use crate::wlan_play::transport::PeerAddr;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+/// A learned address moves to another peer only after its owner stopped sending
+/// from it for this long, so frames echoed by other sites can't steal it
+pub const MAC_MOVE: Duration = Duration::from_secs(10);
+/// Learned addresses are forgotten after this long
+pub const MAC_AGEING: Duration = Duration::from_secs(300);
+
+/// Whether frames of a `from` peer go to a `to` peer of the same room
+pub fn routes(config: &RoutingConfig, from: Mode, to: Mode) -> bool {
+    match (config.routing, from, to) {
+        (_, Mode::Observer, _) => false,
+        (_, _, Mode::Observer) => true,
+        (Routing::Flood, _, _) => true,
+        (Routing::Roles, Mode::Host, Mode::Host) => config.multi_host == MultiHost::Share,
+        (Routing::Roles, Mode::Station, Mode::Station) => false,
+        (Routing::Roles, _, _) => true,
+    }
+}
+
+struct Entry {
+    peer: PeerAddr,
+    seen: Instant,
+}
+
+/// Which peer each 802.11 transmitter address of a room sits behind
+#[derive(Default)]
+pub struct MacTable {
+    entries: HashMap<Mac, Entry>,
+}
+
+impl MacTable {
+    pub fn learn(&mut self, mac: Mac, peer: PeerAddr, now: Instant) {
+        if mac.is_multicast() {
+            return;
+        }
+        match self.entries.get_mut(&mac) {
+            Some(e) if e.peer == peer => e.seen = now,
+            Some(e) if now - e.seen < MAC_MOVE => {}
+            _ => {
+                log::debug!("Learned {:?} behind {}", mac, peer);
+                self.entries.insert(mac, Entry { peer, seen: now });
+            }
+        }
+    }
+    pub fn lookup(&self, mac: &Mac) -> Option<PeerAddr> {
+        self.entries.get(mac).map(|e| e.peer)
+    }
+    pub fn forget(&mut self, peer: PeerAddr) {
+        self.entries.retain(|_, e| e.peer != peer);
+    }
+    pub fn expire(&mut self, now: Instant) {
+        self.entries.retain(|_, e| now - e.seen <= MAC_AGEING);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::prelude::*;
+
+    fn mac(last: u8) -> Mac {
+        Mac::from_bytes((&[2, 0, 0, 0, 0, last], 0)).unwrap().1
+    }
+
+    #[test]
+    fn test_routes() {
+        use Mode::*;
+        let mut config = RoutingConfig::default();
+        assert!(routes(&config, Host, Station));
+        assert!(routes(&config, Station, Host));
+        assert!(routes(&config, Station, Observer));
+        assert!(!routes(&config, Station, Station));
+        assert!(!routes(&config, Host, Host));
+        assert!(!routes(&config, Observer, Host));
+        config.multi_host = MultiHost::Share;
+        assert!(routes(&config, Host, Host));
+        config.routing = Routing::Flood;
+        assert!(routes(&config, Station, Station));
+    }
+
+    #[test]
+    fn test_learn() {
+        let (a, b) = (
+            PeerAddr::Udp(([127, 0, 0, 1], 1).into(), 0),
+            PeerAddr::Udp(([127, 0, 0, 1], 2).into(), 0),
+        );
+        let now = Instant::now();
+        let mut table = MacTable::default();
+        table.learn(mac(1), a, now);
+        assert_eq!(table.lookup(&mac(1)), Some(a));
+        // echoed by b while a is still sending
+        table.learn(mac(1), b, now + Duration::from_secs(1));
+        assert_eq!(table.lookup(&mac(1)), Some(a));
+        // moved to b
+        table.learn(mac(1), b, now + MAC_MOVE + Duration::from_secs(1));
+        assert_eq!(table.lookup(&mac(1)), Some(b));
+        table.forget(b);
+        assert_eq!(table.lookup(&mac(1)), None);
+        // group addresses are never learned
+        let group = Mac::from_bytes((&[0xff; 6], 0)).unwrap().1;
+        table.learn(group.clone(), a, now);
+        assert_eq!(table.lookup(&group), None);
+    }
+}
@@ -0,0 +1,233 @@
+use super::rooms::PeerInfo;
+use super::Server;
+use crate::metrics;
+use crate::wlan_play::protocol::{ErrorCode, SOFTWARE_VERSION};
+use anyhow::Result;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_derive::Serialize;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+#[derive(Serialize)]
+struct SessionView {
+    room: String,
+    peer_id: u32,
+    session_id: String,
+    channel: u32,
+    host: String,
+    local_communication_id: String,
+    scene_id: u16,
+}
+
+#[derive(Serialize)]
+struct RoomInfo {
+    name: String,
+    peers: Vec<PeerInfo>,
+    sessions: Vec<SessionView>,
+    /// role problems, also logged
+    mismatches: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BanInfo {
+    ip: IpAddr,
+    /// None if the ban is permanent
+    remaining_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    uptime_secs: u64,
+    rooms: Vec<RoomInfo>,
+    bans: Vec<BanInfo>,
+}
+
+impl Server {
+    fn rooms_info(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .rooms
+            .keys()
+            .map(|name| RoomInfo {
+                name: name.clone(),
+                peers: self.rooms.room_info(name),
+                sessions: self.sessions_info(Some(name)),
+                mismatches: self.mismatches.get(name).cloned().unwrap_or_default(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+    fn sessions_info(&self, room: Option<&str>) -> Vec<SessionView> {
+        self.rooms
+            .sessions(room)
+            .into_iter()
+            .map(|(room, peer_id, s)| SessionView {
+                room: room.to_string(),
+                peer_id,
+                session_id: hex::encode(s.session_id),
+                channel: s.channel,
+                host: format!("{:?}", s.host),
+                local_communication_id: format!("{:016x}", s.local_communication_id),
+                scene_id: s.scene_id,
+            })
+            .collect()
+    }
+    fn bans_info(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        self.bans
+            .iter()
+            .map(|(ip, until)| BanInfo {
+                ip: *ip,
+                remaining_secs: until.map(|u| u.saturating_duration_since(now).as_secs()),
+            })
+            .collect()
+    }
+}
+
+fn json<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec_pretty(value) {
+        Ok(body) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "error": message }).to_string();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+fn query_secs(req: &Request<Body>) -> Option<Duration> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|kv| kv.strip_prefix("seconds="))
+        .find_map(|v| v.parse().ok())
+        .map(Duration::from_secs)
+}
+
+async fn route(req: Request<Body>, server: &Mutex<Server>) -> Result<Response<Body>> {
+    let path: Vec<_> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let mut server = server.lock().await;
+    let resp = match (req.method(), path.as_slice()) {
+        (&Method::GET, ["metrics"]) => metrics::response(),
+        (&Method::GET, ["api", "status"]) => json(&Status {
+            version: SOFTWARE_VERSION,
+            uptime_secs: server.started.elapsed().as_secs(),
+            rooms: server.rooms_info(),
+            bans: server.bans_info(),
+        }),
+        (&Method::GET, ["api", "rooms"]) => json(&server.rooms_info()),
+        (&Method::GET, ["api", "peers"]) => {
+            let mut peers: Vec<_> = server
+                .rooms
+                .peers
+                .keys()
+                .filter_map(|a| server.rooms.info(*a))
+                .collect();
+            peers.sort_by_key(|p| p.id);
+            json(&peers)
+        }
+        (&Method::GET, ["api", "sessions"]) => json(&server.sessions_info(None)),
+        (&Method::GET, ["api", "bans"]) => json(&server.bans_info()),
+        (&Method::POST, ["api", "peers", id, action @ ("kick" | "ban")]) => {
+            let addr = match id.parse().ok().and_then(|id| server.rooms.find(id)) {
+                Some(addr) => addr,
+                None => return Ok(error(StatusCode::NOT_FOUND, "No such peer")),
+            };
+            if *action == "kick" {
+                server
+                    .kick(addr, ErrorCode::Kicked, "kicked by admin")
+                    .await?;
+            } else {
+                server.ban(addr.ip(), query_secs(&req)).await?;
+            }
+            json(&serde_json::json!({ "ok": true }))
+        }
+        (&Method::DELETE, ["api", "bans", ip]) => match ip.parse() {
+            Ok(ip) if server.bans.remove(&ip).is_some() => {
+                log::info!("Unbanned {}", ip);
+                json(&serde_json::json!({ "ok": true }))
+            }
+            _ => error(StatusCode::NOT_FOUND, "No such ban"),
+        },
+        _ => error(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(resp)
+}
+
+/// The request carries `token`, compared in constant time
+fn has_token(req: &Request<Body>, token: &str) -> bool {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| v.as_bytes().ct_eq(token.as_bytes()).into())
+}
+
+async fn handle(
+    req: Request<Body>,
+    remote: SocketAddr,
+    server: Arc<Mutex<Server>>,
+    token: Arc<Option<String>>,
+) -> Result<Response<Body>, Infallible> {
+    match token.as_ref() {
+        Some(token) if !has_token(&req, token) => {
+            return Ok(error(StatusCode::UNAUTHORIZED, "Bad token"));
+        }
+        // without a token, only local clients may act as admin
+        None if req.method() != Method::GET && !remote.ip().is_loopback() => {
+            return Ok(error(
+                StatusCode::FORBIDDEN,
+                "Admin actions need --http-token from other hosts",
+            ));
+        }
+        _ => {}
+    }
+    Ok(route(req, &server).await.unwrap_or_else(|e| {
+        log::error!("HTTP: {:?}", e);
+        error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }))
+}
+
+/// Serves the status and admin API
+pub async fn serve(
+    addr: SocketAddr,
+    token: Option<String>,
+    server: Arc<Mutex<Server>>,
+) -> Result<()> {
+    let token = Arc::new(token);
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let (server, token) = (server.clone(), token.clone());
+        let remote = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, remote, server.clone(), token.clone())
+            }))
+        }
+    });
+    let http = hyper::Server::try_bind(&addr)?.serve(make_svc);
+    log::info!("HTTP API listening on {}", addr);
+    http.await?;
+    Ok(())
+}
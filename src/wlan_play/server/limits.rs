@@ -0,0 +1,91 @@
+use crate::config::LimitsConfig;
+use tokio::time::{Duration, Instant};
+
+/// Unparsable frames are counted over this window before they lead to a ban
+pub const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// An address is answered an error at most this often, such as to send
+/// `Hello` first or that its protocol version isn't supported
+pub const REFUSE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Refilled at `rate` tokens per second, holds one second worth of tokens
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+    /// Takes `n` tokens, returns false if there are not enough
+    pub fn take(&mut self, n: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens < n as f64 {
+            return false;
+        }
+        self.tokens -= n as f64;
+        true
+    }
+}
+
+/// Packet and byte rate limits of a peer
+pub struct RateLimit {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimit {
+    pub fn new(limits: &LimitsConfig) -> RateLimit {
+        let now = Instant::now();
+        RateLimit {
+            packets: limits.packets_per_sec.map(|r| TokenBucket::new(r, now)),
+            bytes: limits.bytes_per_sec.map(|r| TokenBucket::new(r, now)),
+        }
+    }
+    /// Whether a frame of `len` bytes is within the limits
+    pub fn check(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        if let Some(packets) = &mut self.packets {
+            if !packets.take(1, now) {
+                return false;
+            }
+        }
+        match &mut self.bytes {
+            Some(bytes) => bytes.take(len, now),
+            None => true,
+        }
+    }
+}
+
+/// Bad frames sent by an IP within `STRIKE_WINDOW`
+pub struct Strikes {
+    pub count: u32,
+    pub since: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, now);
+        assert!(bucket.take(10, now));
+        assert!(!bucket.take(1, now));
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.take(5, later));
+        assert!(!bucket.take(1, later));
+        // never more than one second worth
+        let much_later = later + Duration::from_secs(10);
+        assert!(!bucket.take(11, much_later));
+        assert!(bucket.take(10, much_later));
+    }
+}
@@ -0,0 +1,1058 @@
+use super::auth::{Auth, TRAILER_LEN};
+use super::compress::Compressor;
+use super::fragment::{fragment, Reassembler};
+use super::protocol::contexts::{Decoded, Decoder, Encoder};
+use super::protocol::{
+    ErrorCode, Frame, FrameBody, PeerEndpoint, SessionEntry, CAPABILITIES, CAP_BATCH,
+    CAP_COMPRESSION, CAP_DIRECT, CAP_HEADER_CONTEXTS, MAX_ROOM_LEN, PROTOCOL_VERSION,
+    SOFTWARE_VERSION,
+};
+use super::radio::Signal;
+use super::seq::{Seq, SeqTracker};
+use super::transport::{tcp_channels, ws_channels, ws_config, Channels, PeerAddr, MAX_FRAME};
+use crate::config::{LimitsConfig, Mode, RoomConfig, RoutingConfig, ServerConfig};
+use crate::metrics::{self, Component};
+use crate::utils::signal;
+use anyhow::Result;
+use dedup::Dedup;
+use deku::prelude::*;
+use limits::{RateLimit, Strikes, REFUSE_INTERVAL, STRIKE_WINDOW};
+use record::Recorder;
+use rooms::{Peer, PeerStats, Rooms};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    select,
+    sync::{mpsc, Mutex},
+    time::{interval, Duration, Instant},
+};
+
+mod dedup;
+mod forward;
+mod http;
+mod limits;
+mod record;
+mod rooms;
+
+/// Answers to requests that don't need `Hello` are at most this many times
+/// larger than the request, spoofed requests can't be amplified much
+const AMPLIFICATION: usize = 3;
+
+/// The error for a frame of `version`, cut to `max_len` bytes, None if even
+/// the bare error doesn't fit
+fn incompatible(version: u8, max_len: usize) -> Option<FrameBody> {
+    let mut message = format!(
+        "Protocol version {} is not supported, server speaks {}",
+        version, PROTOCOL_VERSION
+    );
+    // frame header and error code
+    message.truncate(max_len.checked_sub(3 + 1)?);
+    Some(FrameBody::error(ErrorCode::Incompatible, message))
+}
+
+struct Server {
+    sockets: Vec<Arc<UdpSocket>>,
+    /// one per pre-shared key, empty if datagrams are not authenticated
+    auths: Vec<Auth>,
+    /// index in `auths` of the key each address authenticates with
+    peer_keys: HashMap<PeerAddr, usize>,
+    routing: RoutingConfig,
+    restrict_rooms: bool,
+    room_configs: HashMap<String, RoomConfig>,
+    /// role mismatches of each room, as last reported
+    mismatches: HashMap<String, Vec<String>>,
+    limits: LimitsConfig,
+    rooms: Rooms,
+    /// outgoing queues of peers connected over TCP or WebSocket
+    streams: HashMap<PeerAddr, mpsc::Sender<Vec<u8>>>,
+    /// banned until, forever if None
+    bans: HashMap<IpAddr, Option<Instant>>,
+    strikes: HashMap<IpAddr, Strikes>,
+    /// when addresses were last answered an error, see `may_refuse`
+    refused: HashMap<PeerAddr, Instant>,
+    dedup: Dedup,
+    recorder: Option<Recorder>,
+    /// largest datagram sent, larger frames are fragmented
+    mtu: usize,
+    reassembler: Reassembler<PeerAddr>,
+    compressor: Compressor,
+    next_id: u32,
+    /// id of the last frame fragmented
+    next_fragment: u32,
+    started: Instant,
+}
+
+impl Server {
+    fn new(sockets: Vec<Arc<UdpSocket>>, config: &ServerConfig) -> Server {
+        Server {
+            sockets,
+            auths: config.keys.iter().map(|k| Auth::new(k)).collect(),
+            peer_keys: HashMap::new(),
+            routing: config.routing,
+            restrict_rooms: config.restrict_rooms,
+            room_configs: config.rooms.clone(),
+            mismatches: HashMap::new(),
+            limits: config.limits.clone(),
+            rooms: Rooms::default(),
+            streams: HashMap::new(),
+            bans: HashMap::new(),
+            strikes: HashMap::new(),
+            refused: HashMap::new(),
+            dedup: Dedup::new(Duration::from_millis(config.dedup_window_ms)),
+            recorder: config.record.clone().map(Recorder::new),
+            mtu: config.mtu,
+            reassembler: Reassembler::new(Component::Relay),
+            compressor: Compressor::new(Component::Relay),
+            next_id: 0,
+            next_fragment: 0,
+            started: Instant::now(),
+        }
+    }
+    /// Index of the key to seal datagrams to `addr` with
+    fn key_of(&self, addr: PeerAddr) -> usize {
+        self.peer_keys.get(&addr).copied().unwrap_or(0)
+    }
+    fn seal(&mut self, bytes: Vec<u8>, key: usize) -> Result<Vec<u8>> {
+        match self.auths.get_mut(key) {
+            Some(auth) => auth.seal(bytes),
+            None => Ok(bytes),
+        }
+    }
+    async fn send_bytes(&mut self, bytes: &[u8], addr: PeerAddr) -> Result<()> {
+        match addr {
+            PeerAddr::Udp(a, socket) => {
+                self.sockets[socket].send_to(bytes, a).await?;
+            }
+            _ => match self.streams.get(&addr) {
+                Some(tx) if tx.try_send(bytes.to_vec()).is_ok() => {}
+                _ => {
+                    log::debug!(
+                        "Dropped frame to {}, connection is congested or closed",
+                        addr
+                    );
+                    return Ok(());
+                }
+            },
+        }
+        metrics::tx(Component::Relay, bytes.len());
+        if let Some(peer) = self.rooms.peers.get_mut(&addr) {
+            peer.stats.tx_packets += 1;
+            peer.stats.tx_bytes += bytes.len() as u64;
+        }
+        Ok(())
+    }
+    /// Seals a serialized frame with `key`, in fragments if it exceeds the MTU
+    fn seal_frame(&mut self, frame: Vec<u8>, key: usize) -> Result<Vec<Vec<u8>>> {
+        let overhead = if self.auths.is_empty() {
+            0
+        } else {
+            TRAILER_LEN
+        };
+        let next_fragment = &mut self.next_fragment;
+        let datagrams = fragment(
+            Component::Relay,
+            frame,
+            self.mtu.saturating_sub(overhead),
+            0,
+            || {
+                *next_fragment = next_fragment.wrapping_add(1);
+                *next_fragment
+            },
+        )?;
+        datagrams.into_iter().map(|d| self.seal(d, key)).collect()
+    }
+    async fn send(&mut self, body: FrameBody, addr: PeerAddr) -> Result<()> {
+        let key = self.key_of(addr);
+        for bytes in self.seal_frame(Frame::try_from(body)?.to_bytes()?, key)? {
+            self.send_bytes(&bytes, addr).await?;
+        }
+        Ok(())
+    }
+    fn takes(&self, addr: PeerAddr, capability: u32) -> bool {
+        self.rooms
+            .peers
+            .get(&addr)
+            .is_some_and(|p| p.capabilities & capability != 0)
+    }
+    /// The `Data` or `ContextData` frame in the payload of a `Compressed` one
+    /// from `addr`
+    fn decompress(&mut self, addr: PeerAddr, data: &[u8]) -> Option<(Vec<u8>, Frame)> {
+        let plain = self.compressor.decompress(data).ok()?;
+        let frame = match Frame::from_bytes((&plain, 0)) {
+            Ok((_, frame))
+                if frame.version == PROTOCOL_VERSION
+                    && matches!(
+                        frame.body,
+                        FrameBody::Data { .. } | FrameBody::ContextData { .. }
+                    ) =>
+            {
+                frame
+            }
+            _ => return None,
+        };
+        if let Some(peer) = self.rooms.peers.get_mut(&addr) {
+            peer.stats.rx_compression.add(plain.len(), data.len());
+        }
+        Some((plain, frame))
+    }
+    /// The `Data` frame of a `ContextData` one from `addr`
+    fn expand(&mut self, addr: PeerAddr, body: FrameBody) -> Decoded {
+        match self.rooms.peers.get_mut(&addr) {
+            Some(peer) => peer.rx_contexts.decode(body, Instant::now()),
+            None => Decoded::Dropped,
+        }
+    }
+    /// A `Data` frame to `addr` with header contexts and compressed, as far
+    /// as it takes them
+    fn pack_for(&mut self, frame: Frame, addr: PeerAddr) -> Result<Frame> {
+        let frame = match self.rooms.peers.get_mut(&addr) {
+            Some(peer) if peer.capabilities & CAP_HEADER_CONTEXTS != 0 => {
+                Frame::try_from(peer.tx_contexts.encode(frame.body))?
+            }
+            _ => frame,
+        };
+        self.compress_for(frame, addr)
+    }
+    /// A `Data` or `ContextData` frame to `addr` compressed, if it takes
+    /// compressed frames and it gets smaller
+    fn compress_for(&mut self, frame: Frame, addr: PeerAddr) -> Result<Frame> {
+        if !matches!(
+            frame.body,
+            FrameBody::Data { .. } | FrameBody::ContextData { .. }
+        ) || !self.takes(addr, CAP_COMPRESSION)
+        {
+            return Ok(frame);
+        }
+        let plain = frame.to_bytes()?;
+        let data = match self.compressor.compress(&plain) {
+            Some(data) => data,
+            None => return Ok(frame),
+        };
+        if let Some(peer) = self.rooms.peers.get_mut(&addr) {
+            peer.stats.tx_compression.add(plain.len(), data.len());
+        }
+        Frame::try_from(FrameBody::Compressed { data })
+    }
+    /// Peers a `Data` or `Encrypted` frame from `addr` goes to, none if it is dropped
+    fn route(&mut self, addr: PeerAddr, body: &FrameBody) -> Vec<PeerAddr> {
+        let peer = match self.rooms.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return vec![],
+        };
+        let (peer_id, seq) = match body {
+            _ if peer.role == Mode::Observer => {
+                log::debug!("Dropped frame from observer {}", addr);
+                return vec![];
+            }
+            FrameBody::Data { peer_id, seq, .. } | FrameBody::Encrypted { peer_id, seq, .. } => {
+                (*peer_id, *seq)
+            }
+            _ => {
+                log::debug!("{} batched a {:?}", addr, body);
+                return vec![];
+            }
+        };
+        if peer_id != peer.id {
+            log::debug!("{} sent a frame as peer {}", addr, peer_id);
+            return vec![];
+        }
+        if metrics::seq(Component::Relay, &mut peer.seq, seq) == Seq::Duplicate {
+            return vec![];
+        }
+        if let FrameBody::Data {
+            channel,
+            data,
+            radio,
+            ..
+        } = body
+        {
+            let now = Instant::now();
+            if self
+                .dedup
+                .is_duplicate(&peer.room, addr, *channel, data, now)
+            {
+                peer.stats.duplicates += 1;
+                metrics::DUPLICATES.inc();
+                return vec![];
+            }
+            if let Some(radio) = radio {
+                peer.signal.add(radio);
+            }
+            if let Some(recorder) = &mut self.recorder {
+                let comment = format!("peer {} {} ({:?})", peer.id, addr, peer.role);
+                let radio = radio.as_ref();
+                if let Err(e) = recorder.record(&peer.room, *channel, data, radio, &comment) {
+                    log::error!("Failed to record room {:?}: {:?}", peer.room, e);
+                }
+            }
+        }
+        let mut targets = match body {
+            FrameBody::Data { data, .. } => self.rooms.targets(addr, data),
+            _ => self.rooms.neighbors(addr),
+        };
+        let sender = &self.rooms.peers[&addr];
+        let routing = self.routing(&sender.room);
+        let peers = &self.rooms.peers;
+        targets.retain(|a| {
+            let peer = &peers[a];
+            !sender.direct.contains(&peer.id) && forward::routes(&routing, sender.role, peer.role)
+        });
+        targets
+    }
+    /// Why `addr` can't join `room`, if it can't
+    fn refuse(&self, addr: PeerAddr, room: &str) -> Option<&'static str> {
+        if room.len() > MAX_ROOM_LEN {
+            return Some("room name is too long");
+        }
+        let rejoin = self.rooms.peers.contains_key(&addr);
+        if matches!(self.limits.max_peers, Some(max) if !rejoin && self.rooms.peers.len() >= max) {
+            return Some("server is full");
+        }
+        let max_peers = match self.room_configs.get(room) {
+            Some(config) => config.max_peers.or(self.limits.max_peers_per_room),
+            None if self.restrict_rooms => return Some("no such room"),
+            None => self.limits.max_peers_per_room,
+        };
+        let peers = self
+            .rooms
+            .rooms
+            .get(room)
+            .map_or(0, |r| r.iter().filter(|a| **a != addr).count());
+        match max_peers {
+            Some(max) if peers >= max => Some("room is full"),
+            _ => None,
+        }
+    }
+    fn routing(&self, room: &str) -> RoutingConfig {
+        self.room_configs
+            .get(room)
+            .and_then(|c| c.routing)
+            .unwrap_or(self.routing)
+    }
+    /// Logs new role mismatches of the rooms
+    fn report_mismatches(&mut self) {
+        let mut mismatches = HashMap::new();
+        for room in self.rooms.rooms.keys() {
+            let found = self.rooms.mismatches(room, &self.routing(room));
+            let reported = self.mismatches.get(room);
+            for m in &found {
+                if !matches!(reported, Some(r) if r.contains(m)) {
+                    log::warn!("Room {:?}: {}", room, m);
+                }
+            }
+            if !found.is_empty() {
+                mismatches.insert(room.clone(), found);
+            }
+        }
+        self.mismatches = mismatches;
+    }
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.bans.get(&ip) {
+            Some(Some(until)) => *until > Instant::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+    /// Removes the peer and tells it why
+    async fn kick(&mut self, addr: PeerAddr, code: ErrorCode, reason: &str) -> Result<()> {
+        self.send(FrameBody::error(code, reason), addr).await?;
+        self.rooms.leave(addr, reason);
+        Ok(())
+    }
+    async fn ban(&mut self, ip: IpAddr, duration: Option<Duration>) -> Result<()> {
+        match duration {
+            Some(d) => log::info!("Banned {} for {:?}", ip, d),
+            None => log::info!("Banned {}", ip),
+        }
+        self.bans.insert(ip, duration.map(|d| Instant::now() + d));
+        for addr in self.rooms.peers_from(ip) {
+            self.kick(addr, ErrorCode::Banned, "banned").await?;
+        }
+        Ok(())
+    }
+    /// Sends the peers of changed rooms to their members that may reach them directly
+    async fn announce(&mut self) -> Result<()> {
+        for room in self.rooms.take_changed() {
+            let members: Vec<_> = self
+                .rooms
+                .rooms
+                .get(&room)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect();
+            let routing = self.routing(&room);
+            for addr in &members {
+                let member = &self.rooms.peers[addr];
+                if member.capabilities & CAP_DIRECT == 0 {
+                    continue;
+                }
+                // direct paths only to the peers its frames go to
+                let peers: Vec<_> = members
+                    .iter()
+                    .filter(|a| {
+                        *a != addr
+                            && forward::routes(&routing, member.role, self.rooms.peers[a].role)
+                    })
+                    .map(|a| {
+                        let peer = &self.rooms.peers[a];
+                        PeerEndpoint::new(peer.id, peer.endpoint)
+                    })
+                    .collect();
+                let body = FrameBody::Peers {
+                    count: peers.len() as u16,
+                    peers,
+                };
+                self.send(body, *addr).await?;
+            }
+        }
+        Ok(())
+    }
+    /// Counts a bad frame from `ip`, bans it when there are too many
+    async fn strike(&mut self, ip: IpAddr) -> Result<()> {
+        let max = self.limits.max_bad_frames;
+        if max == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let strikes = self.strikes.entry(ip).or_insert(Strikes {
+            count: 0,
+            since: now,
+        });
+        if now - strikes.since > STRIKE_WINDOW {
+            strikes.count = 0;
+            strikes.since = now;
+        }
+        strikes.count += 1;
+        if strikes.count >= max {
+            self.strikes.remove(&ip);
+            log::warn!("{} sent {} bad frames within a minute", ip, max);
+            let duration = Duration::from_secs(self.limits.bad_frames_ban);
+            self.ban(ip, Some(duration)).await?;
+        }
+        Ok(())
+    }
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.rooms.expire(peer_timeout);
+        self.report_mismatches();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush(&self.rooms.rooms.keys().collect());
+        }
+        for auth in &mut self.auths {
+            auth.expire();
+        }
+        let peers = &self.rooms.peers;
+        self.peer_keys.retain(|addr, _| peers.contains_key(addr));
+        self.strikes
+            .retain(|_, s| s.since.elapsed() <= STRIKE_WINDOW);
+        let now = Instant::now();
+        self.refused
+            .retain(|_, told| now.duration_since(*told) < REFUSE_INTERVAL);
+        self.dedup.expire(now);
+        self.bans.retain(|ip, until| match until {
+            Some(until) if *until <= now => {
+                log::info!("Ban of {} expired", ip);
+                false
+            }
+            _ => true,
+        });
+    }
+    /// Says goodbye to all peers and closes the recordings
+    async fn shutdown(&mut self) -> Result<()> {
+        let peers: Vec<_> = self.rooms.peers.keys().cloned().collect();
+        for addr in peers {
+            if let Err(e) = self
+                .send(FrameBody::goodbye("server is shutting down"), addr)
+                .await
+            {
+                log::warn!("Failed to say goodbye to {}: {:?}", addr, e);
+            }
+            self.rooms.leave(addr, "server is shutting down");
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush(&HashSet::new());
+        }
+        Ok(())
+    }
+    /// Whether `addr` may be answered an error. At most once per
+    /// `REFUSE_INTERVAL`, so spoofed sources can't use the server to flood
+    fn may_refuse(&mut self, addr: PeerAddr) -> bool {
+        let now = Instant::now();
+        match self.refused.get(&addr) {
+            Some(told) if now.duration_since(*told) < REFUSE_INTERVAL => false,
+            _ => {
+                self.refused.insert(addr, now);
+                true
+            }
+        }
+    }
+    /// Tells `addr` its protocol version isn't supported, in no more bytes
+    /// than the `request_len` bytes of its frame
+    async fn refuse_version(
+        &mut self,
+        addr: PeerAddr,
+        version: u8,
+        request_len: usize,
+    ) -> Result<()> {
+        match incompatible(version, request_len) {
+            Some(error) if self.may_refuse(addr) => self.send(error, addr).await,
+            _ => Ok(()),
+        }
+    }
+    async fn handle(&mut self, addr: PeerAddr, buf: &[u8]) -> Result<()> {
+        if self.is_banned(addr.ip()) {
+            return Ok(());
+        }
+        metrics::rx(Component::Relay, buf.len());
+        if buf.len() > self.limits.max_datagram_size {
+            metrics::OVERSIZED.inc();
+            log::debug!("Dropped datagram of {} bytes from {}", buf.len(), addr);
+            return self.strike(addr.ip()).await;
+        }
+        let buf = if self.auths.is_empty() {
+            buf
+        } else {
+            let mut opened = Err(anyhow::anyhow!("No key"));
+            for (i, auth) in self.auths.iter_mut().enumerate() {
+                opened = auth.open(buf).map(|buf| (i, buf));
+                if opened.is_ok() {
+                    break;
+                }
+            }
+            match opened {
+                Ok((key, buf)) => {
+                    if self.peer_keys.get(&addr) != Some(&key) {
+                        self.peer_keys.insert(addr, key);
+                    }
+                    buf
+                }
+                Err(e) => {
+                    metrics::inc(&metrics::AUTH_FAILURES, Component::Relay);
+                    log::debug!("Dropped datagram from {}: {}", addr, e);
+                    return Ok(());
+                }
+            }
+        };
+        let (_, frame) = match Frame::from_bytes((buf, 0)) {
+            Ok(f) => f,
+            Err(e) => {
+                metrics::inc(&metrics::PARSE_ERRORS, Component::Relay);
+                log::debug!("Bad frame from {}: {:?} {:02x?}", addr, e, buf);
+                return self.strike(addr.ip()).await;
+            }
+        };
+        if frame.version != PROTOCOL_VERSION {
+            log::warn!("{} speaks protocol version {}", addr, frame.version);
+            // never answer an error, two relays would bounce them forever
+            if matches!(frame.body, FrameBody::Error { .. }) {
+                return Ok(());
+            }
+            return self.refuse_version(addr, frame.version, buf.len()).await;
+        }
+        let reassembled;
+        // fragments are charged as they come, so those of frames never completed count too
+        let mut charged = false;
+        let (buf, frame) = match frame {
+            Frame {
+                body:
+                    FrameBody::Fragment {
+                        id,
+                        index,
+                        count,
+                        data,
+                        ..
+                    },
+                ..
+            } => {
+                let peer = match self.rooms.peers.get_mut(&addr) {
+                    Some(peer) => peer,
+                    None => {
+                        log::debug!("{} sent a fragment before Hello", addr);
+                        return Ok(());
+                    }
+                };
+                if !peer.rate.check(buf.len()) {
+                    peer.stats.rate_limited += 1;
+                    metrics::RATE_LIMITED.inc();
+                    return Ok(());
+                }
+                charged = true;
+                let now = Instant::now();
+                reassembled = match self.reassembler.add(addr, id, index, count, data, now) {
+                    Some(r) => r,
+                    None => return Ok(()),
+                };
+                match Frame::from_bytes((&reassembled, 0)) {
+                    Ok((_, frame))
+                        if frame.version == PROTOCOL_VERSION
+                            && !matches!(frame.body, FrameBody::Fragment { .. }) =>
+                    {
+                        (&reassembled[..], frame)
+                    }
+                    _ => {
+                        metrics::inc(&metrics::PARSE_ERRORS, Component::Relay);
+                        log::debug!("Bad reassembled frame from {}", addr);
+                        return self.strike(addr.ip()).await;
+                    }
+                }
+            }
+            frame => (buf, frame),
+        };
+        let decompressed;
+        let (buf, frame) = match frame {
+            Frame {
+                body: FrameBody::Compressed { data },
+                ..
+            } => {
+                if !self.rooms.peers.contains_key(&addr) {
+                    log::debug!("{} sent a compressed frame before Hello", addr);
+                    return Ok(());
+                }
+                match self.decompress(addr, &data) {
+                    Some((plain, frame)) => {
+                        decompressed = plain;
+                        (&decompressed[..], frame)
+                    }
+                    None => {
+                        metrics::inc(&metrics::PARSE_ERRORS, Component::Relay);
+                        log::debug!("Bad compressed frame from {}", addr);
+                        return self.strike(addr.ip()).await;
+                    }
+                }
+            }
+            frame => (buf, frame),
+        };
+        let expanded;
+        let (buf, frame) = match frame {
+            Frame {
+                body: body @ FrameBody::ContextData { .. },
+                ..
+            } => match self.expand(addr, body) {
+                Decoded::Data(body) => {
+                    let frame = Frame::try_from(body)?;
+                    expanded = frame.to_bytes()?;
+                    (&expanded[..], frame)
+                }
+                Decoded::Resync(context) => {
+                    return self.send(FrameBody::ContextResync { context }, addr).await
+                }
+                Decoded::Dropped => {
+                    log::debug!("Dropped frame of an unknown header context from {}", addr);
+                    return Ok(());
+                }
+            },
+            frame => (buf, frame),
+        };
+        match frame.body {
+            FrameBody::Hello {
+                version,
+                role,
+                capabilities,
+                software,
+                room,
+                ..
+            } => {
+                if version != PROTOCOL_VERSION {
+                    log::warn!("{} speaks protocol version {}", addr, version);
+                    return self.refuse_version(addr, version, buf.len()).await;
+                }
+                let room = String::from_utf8_lossy(&room).into_owned();
+                let id = match self.rooms.peers.get_mut(&addr) {
+                    // Hello sent again because Welcome was lost
+                    Some(peer) if peer.room == room && peer.role == role => {
+                        peer.rejoin();
+                        peer.id
+                    }
+                    _ => {
+                        if let Some(reason) = self.refuse(addr, &room) {
+                            log::info!("Refused {} in room {:?}: {}", addr, room, reason);
+                            let error = FrameBody::error(ErrorCode::Refused, reason);
+                            return self.send(error, addr).await;
+                        }
+                        self.next_id += 1;
+                        self.rooms.join(
+                            addr,
+                            Peer {
+                                id: self.next_id,
+                                room,
+                                role,
+                                capabilities,
+                                software: String::from_utf8_lossy(&software).into_owned(),
+                                joined: Instant::now(),
+                                last_seen: Instant::now(),
+                                stats: PeerStats::default(),
+                                rate: RateLimit::new(&self.limits),
+                                endpoint: match addr {
+                                    PeerAddr::Udp(a, _) if capabilities & CAP_DIRECT != 0 => {
+                                        Some(a)
+                                    }
+                                    _ => None,
+                                },
+                                direct: HashSet::new(),
+                                sessions: HashMap::new(),
+                                seq: SeqTracker::default(),
+                                signal: Signal::default(),
+                                tx_contexts: Encoder::new(Component::Relay),
+                                rx_contexts: Decoder::default(),
+                            },
+                        );
+                        self.next_id
+                    }
+                };
+                let welcome = FrameBody::Welcome {
+                    peer_id: id,
+                    capabilities: CAPABILITIES,
+                    software: SOFTWARE_VERSION.as_bytes().to_vec(),
+                };
+                self.send(welcome, addr).await?;
+                self.announce().await?;
+            }
+            FrameBody::ListSessions {
+                all_rooms, room, ..
+            } => {
+                let room = String::from_utf8_lossy(&room).into_owned();
+                let room = Some(room.as_str()).filter(|_| all_rooms == 0);
+                let mut budget = AMPLIFICATION * buf.len();
+                let sessions: Vec<_> = self
+                    .rooms
+                    .sessions(room)
+                    .into_iter()
+                    .filter_map(|(room, id, s)| SessionEntry::new(id, room, s.clone()).ok())
+                    .take_while(|e| {
+                        budget = budget.saturating_sub(e.size());
+                        budget > 0
+                    })
+                    .collect();
+                let sessions = FrameBody::Sessions {
+                    count: sessions.len() as u16,
+                    sessions,
+                };
+                self.send(sessions, addr).await?;
+            }
+            FrameBody::Goodbye { reason } => {
+                let reason = format!("said goodbye ({})", String::from_utf8_lossy(&reason));
+                if self.rooms.leave(addr, &reason).is_some() {
+                    self.announce().await?;
+                }
+            }
+            body => {
+                let peer = match self.rooms.touch(addr) {
+                    Some(peer) => peer,
+                    None => {
+                        log::debug!("{} sent {:?} before Hello", addr, frame.frame_type);
+                        // never answer an error, two relays would bounce them forever
+                        if matches!(body, FrameBody::Error { .. }) || !self.may_refuse(addr) {
+                            return Ok(());
+                        }
+                        let error = FrameBody::error(ErrorCode::NotJoined, "Send Hello first");
+                        return self.send(error, addr).await;
+                    }
+                };
+                peer.stats.rx_packets += 1;
+                peer.stats.rx_bytes += buf.len() as u64;
+                if !charged && !peer.rate.check(buf.len()) {
+                    peer.stats.rate_limited += 1;
+                    metrics::RATE_LIMITED.inc();
+                    return Ok(());
+                }
+                match body {
+                    FrameBody::Keepalive => self.send(FrameBody::Keepalive, addr).await?,
+                    FrameBody::Direct { peer_ids } => {
+                        peer.direct = peer_ids.into_iter().collect();
+                    }
+                    FrameBody::Session { session } => peer.publish(session),
+                    FrameBody::ContextResync { context } => peer.tx_contexts.resync(context),
+                    FrameBody::Ping { from, .. } | FrameBody::Pong { from, .. }
+                        if from != peer.id =>
+                    {
+                        log::debug!("{} sent a ping from peer {}", addr, from);
+                    }
+                    FrameBody::Ping {
+                        from,
+                        to: 0,
+                        timestamp,
+                    } => {
+                        let pong = FrameBody::Pong {
+                            from: 0,
+                            to: from,
+                            timestamp,
+                        };
+                        self.send(pong, addr).await?;
+                    }
+                    FrameBody::Ping { to, .. } | FrameBody::Pong { to, .. } => {
+                        let room = peer.room.clone();
+                        match self.rooms.find(to) {
+                            Some(a) if self.rooms.peers[&a].room == room => {
+                                let key = self.key_of(a);
+                                let out = self.seal(buf.to_vec(), key)?;
+                                self.send_bytes(&out, a).await?;
+                            }
+                            _ => log::debug!("{} pinged unknown peer {}", addr, to),
+                        }
+                    }
+                    FrameBody::Data { .. } | FrameBody::Encrypted { .. } => {
+                        let is_data = matches!(body, FrameBody::Data { .. });
+                        let targets = self.route(addr, &body);
+                        // header contexts are per peer, so are their frames
+                        let (own, targets): (Vec<_>, Vec<_>) = targets
+                            .into_iter()
+                            .partition(|a| is_data && self.takes(*a, CAP_HEADER_CONTEXTS));
+                        for a in own {
+                            let frame = self.pack_for(Frame::try_from(body.clone())?, a)?;
+                            self.send(frame.body, a).await?;
+                        }
+                        let mut targets: Vec<_> = targets
+                            .into_iter()
+                            .map(|a| (self.key_of(a), is_data && self.takes(a, CAP_COMPRESSION), a))
+                            .collect();
+                        // compressed once for all the peers that take it
+                        let mut compressed = None;
+                        if targets.iter().any(|(_, compress, _)| *compress) {
+                            if let Some(data) = self.compressor.compress(buf) {
+                                let len = data.len();
+                                let frame = Frame::try_from(FrameBody::Compressed { data })?;
+                                compressed = Some((len, frame.to_bytes()?));
+                            }
+                        }
+                        // grouped by key and compression so each group seals the frame once
+                        targets.sort_by_key(|(key, compress, _)| (*key, *compress));
+                        let mut group = None;
+                        let mut datagrams = vec![];
+                        for (key, compress, a) in targets {
+                            let frame = match &compressed {
+                                Some((len, frame)) if compress => {
+                                    if let Some(peer) = self.rooms.peers.get_mut(&a) {
+                                        peer.stats.tx_compression.add(buf.len(), *len);
+                                    }
+                                    frame
+                                }
+                                _ => buf,
+                            };
+                            if group != Some((key, compress)) {
+                                group = Some((key, compress));
+                                datagrams = self.seal_frame(frame.to_vec(), key)?;
+                            }
+                            for out in &datagrams {
+                                self.send_bytes(out, a).await?;
+                            }
+                        }
+                    }
+                    FrameBody::Batch { frames, .. } => {
+                        let mut batches = HashMap::<PeerAddr, Vec<Frame>>::new();
+                        for frame in frames {
+                            let frame = match frame.body {
+                                FrameBody::Compressed { data } => {
+                                    match self.decompress(addr, &data) {
+                                        Some((_, frame)) => frame,
+                                        None => {
+                                            metrics::inc(&metrics::PARSE_ERRORS, Component::Relay);
+                                            log::debug!("Bad compressed frame from {}", addr);
+                                            continue;
+                                        }
+                                    }
+                                }
+                                _ => frame,
+                            };
+                            let frame = match self.expand(addr, frame.body) {
+                                Decoded::Data(body) => Frame::try_from(body)?,
+                                Decoded::Resync(context) => {
+                                    let resync = FrameBody::ContextResync { context };
+                                    self.send(resync, addr).await?;
+                                    continue;
+                                }
+                                Decoded::Dropped => continue,
+                            };
+                            for a in self.route(addr, &frame.body) {
+                                batches.entry(a).or_default().push(frame.clone());
+                            }
+                        }
+                        // unbatched for peers that don't take batches
+                        for (a, frames) in batches {
+                            let frames = frames
+                                .into_iter()
+                                .map(|f| self.pack_for(f, a))
+                                .collect::<Result<Vec<_>>>()?;
+                            if frames.len() > 1
+                                && self.rooms.peers[&a].capabilities & CAP_BATCH != 0
+                            {
+                                self.send(FrameBody::batch(frames), a).await?;
+                            } else {
+                                for frame in frames {
+                                    self.send(frame.body, a).await?;
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Relays frames of a TCP or WebSocket connection until it is closed
+async fn serve_stream(server: Arc<Mutex<Server>>, addr: PeerAddr, (tx, mut rx): Channels) {
+    log::debug!("{} connected", addr);
+    server.lock().await.streams.insert(addr, tx);
+    while let Some(frame) = rx.recv().await {
+        if let Err(e) = server.lock().await.handle(addr, &frame).await {
+            log::error!("{}: {:?}", addr, e);
+            break;
+        }
+    }
+    let mut server = server.lock().await;
+    server.streams.remove(&addr);
+    server.rooms.leave(addr, "disconnected");
+}
+
+async fn accept_tcp(listener: TcpListener, server: Arc<Mutex<Server>>) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        if server.lock().await.is_banned(remote.ip()) {
+            continue;
+        }
+        stream.set_nodelay(true)?;
+        let channels = tcp_channels(stream);
+        tokio::spawn(serve_stream(
+            server.clone(),
+            PeerAddr::Tcp(remote),
+            channels,
+        ));
+    }
+}
+
+async fn accept_ws(listener: TcpListener, server: Arc<Mutex<Server>>) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        if server.lock().await.is_banned(remote.ip()) {
+            continue;
+        }
+        stream.set_nodelay(true)?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            match tokio_tungstenite::accept_async_with_config(stream, Some(ws_config())).await {
+                Ok(ws) => serve_stream(server, PeerAddr::Ws(remote), ws_channels(ws)).await,
+                Err(e) => log::debug!("WebSocket handshake with {} failed: {}", remote, e),
+            }
+        });
+    }
+}
+
+async fn recv_udp(index: usize, socket: Arc<UdpSocket>, server: Arc<Mutex<Server>>) -> Result<()> {
+    let mut buf = vec![0; MAX_FRAME];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let addr = PeerAddr::Udp(addr, index);
+        // e.g. a peer whose address became unreachable, the others go on
+        if let Err(e) = server.lock().await.handle(addr, &buf[..len]).await {
+            log::warn!("{}: {:?}", addr, e);
+        }
+    }
+}
+
+/// The relay, run by `wlan_play_server` or embedded in a client
+pub struct Relay {
+    server: Arc<Mutex<Server>>,
+    sockets: Vec<Arc<UdpSocket>>,
+    peer_timeout: Duration,
+}
+
+impl Relay {
+    /// Binds the addresses of `config`, TCP, WebSocket and HTTP are served right away
+    pub async fn bind(config: &ServerConfig) -> Result<Relay> {
+        let mut sockets = vec![];
+        for addr in &config.udp {
+            sockets.push(Arc::new(UdpSocket::bind(addr).await?));
+            log::info!("Listening on udp://{}", addr);
+        }
+        metrics::init_server();
+        let server = Arc::new(Mutex::new(Server::new(sockets.clone(), config)));
+        for addr in &config.tcp {
+            let listener = TcpListener::bind(addr).await?;
+            log::info!("Listening on tcp://{}", addr);
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = accept_tcp(listener, server).await {
+                    log::error!("TCP listener stopped: {:?}", e);
+                }
+            });
+        }
+        for addr in &config.ws {
+            let listener = TcpListener::bind(addr).await?;
+            log::info!("Listening on ws://{}", addr);
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = accept_ws(listener, server).await {
+                    log::error!("WebSocket listener stopped: {:?}", e);
+                }
+            });
+        }
+        if let Some(addr) = config.http {
+            let (server, token) = (server.clone(), config.http_token.clone());
+            tokio::spawn(async move {
+                if let Err(e) = http::serve(addr, token, server).await {
+                    log::error!("HTTP server stopped: {:?}", e);
+                }
+            });
+        }
+        Ok(Relay {
+            server,
+            sockets,
+            peer_timeout: Duration::from_secs(config.peer_timeout),
+        })
+    }
+    /// Address a client on this machine reaches the first UDP socket at
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let socket = self
+            .sockets
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("The relay has no UDP address"))?;
+        let mut addr = socket.local_addr()?;
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => {}
+        }
+        Ok(addr)
+    }
+    /// Relays UDP datagrams and expires peers, until a socket fails or
+    /// `shutdown` resolves
+    pub async fn run(self, shutdown: impl Future<Output = Result<()>>) -> Result<()> {
+        let server = self.server;
+        let udp = self
+            .sockets
+            .into_iter()
+            .enumerate()
+            .map(|(i, socket)| recv_udp(i, socket, server.clone()));
+        let peer_timeout = self.peer_timeout;
+        let mut expire = interval(Duration::from_secs(1));
+        let expire = async {
+            loop {
+                expire.tick().await;
+                let mut server = server.lock().await;
+                server.expire(peer_timeout);
+                if let Err(e) = server.announce().await {
+                    log::error!("Failed to announce peers: {:?}", e);
+                }
+            }
+        };
+        select! {
+            r = futures::future::try_join_all(udp) => r.map(|_| ()),
+            _ = expire => Ok(()),
+            r = shutdown => {
+                r?;
+                log::info!("Shutting down");
+                server.lock().await.shutdown().await
+            }
+        }
+    }
+}
+
+pub async fn main(config: ServerConfig) -> Result<()> {
+    Relay::bind(&config).await?.run(signal::shutdown()).await
+}
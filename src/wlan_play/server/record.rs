@@ -0,0 +1,113 @@
+use crate::agent::RadioInfo;
+use crate::utils::pcapng::Writer;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Longest part of a file name taken from the room name, as file names have at most 255 bytes
+const MAX_NAME_ROOM: usize = 64;
+/// Files tried for a room before giving up
+const MAX_NAME_TRIES: u32 = 100;
+
+/// Records relayed frames into one pcapng file per room
+pub struct Recorder {
+    dir: PathBuf,
+    files: HashMap<String, Writer<BufWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> Recorder {
+        Recorder {
+            dir,
+            files: HashMap::new(),
+        }
+    }
+    /// `<room>-<hash>-<secs>`, the hash of the raw room name telling apart rooms with the same
+    /// sanitized name
+    fn file_stem(room: &str, secs: u64) -> String {
+        let sanitized: String = room
+            .chars()
+            .take(MAX_NAME_ROOM)
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let hash = Sha256::digest(room.as_bytes());
+        format!("{}-{}-{}", sanitized, hex::encode(&hash[..4]), secs)
+    }
+    /// Creates a new file for the room, never overwriting another recording
+    fn create(dir: &Path, room: &str) -> Result<(PathBuf, File)> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let stem = Self::file_stem(room, secs);
+        for n in 0..MAX_NAME_TRIES {
+            let path = match n {
+                0 => dir.join(format!("{}.pcapng", stem)),
+                n => dir.join(format!("{}-{}.pcapng", stem, n)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(anyhow!("No free file name for recording {}", stem))
+    }
+    pub fn record(
+        &mut self,
+        room: &str,
+        channel: u32,
+        data: &[u8],
+        radio: Option<&RadioInfo>,
+        comment: &str,
+    ) -> Result<()> {
+        if !self.files.contains_key(room) {
+            let (path, file) = Self::create(&self.dir, room)?;
+            log::info!("Recording room {:?} to {}", room, path.display());
+            let writer = Writer::new(BufWriter::new(file))?;
+            self.files.insert(room.to_string(), writer);
+        }
+        let writer = self.files.get_mut(room).unwrap();
+        writer.write(channel, data, radio, Some(comment))
+    }
+    /// Flushes the files, and closes those of rooms that are gone
+    pub fn flush(&mut self, rooms: &HashSet<&String>) {
+        self.files.retain(|room, writer| {
+            if let Err(e) = writer.flush() {
+                log::error!("Failed to write recording of room {:?}: {:?}", room, e);
+            }
+            rooms.contains(room)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_names() {
+        let a = Recorder::file_stem("a.b", 1);
+        let b = Recorder::file_stem("a_b", 1);
+        assert!(a.starts_with("a_b-") && a.ends_with("-1"));
+        assert_ne!(a, b);
+        let long = "\u{e9}".repeat(200);
+        assert!(Recorder::file_stem(&long, u64::MAX).len() < 100);
+
+        let dir = std::env::temp_dir().join(format!("wlan_play_record_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, _) = Recorder::create(&dir, "room").unwrap();
+        let (second, _) = Recorder::create(&dir, "room").unwrap();
+        assert_ne!(first, second);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+use super::forward::MacTable;
+use super::limits::RateLimit;
+use crate::config::{Mode, MultiHost, Routing, RoutingConfig};
+use crate::metrics;
+use crate::utils::ieee80211;
+use crate::wlan_play::compress::CompressionStats;
+use crate::wlan_play::protocol::contexts::{Decoder, Encoder};
+use crate::wlan_play::protocol::{SessionInfo, CAP_ENCRYPTION, SESSION_INTERVAL};
+use crate::wlan_play::radio::Signal;
+use crate::wlan_play::seq::{SeqStats, SeqTracker};
+use crate::wlan_play::transport::PeerAddr;
+use deku::prelude::*;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{Duration, Instant};
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct PeerStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// frames dropped by the rate limit
+    pub rate_limited: u64,
+    /// frames dropped because another peer relayed them first
+    pub duplicates: u64,
+    /// data frames it sent compressed
+    pub rx_compression: CompressionStats,
+    /// data frames compressed for it
+    pub tx_compression: CompressionStats,
+}
+
+pub struct Peer {
+    pub id: u32,
+    pub room: String,
+    pub role: Mode,
+    pub capabilities: u32,
+    pub software: String,
+    pub joined: Instant,
+    pub last_seen: Instant,
+    pub stats: PeerStats,
+    pub rate: RateLimit,
+    /// public UDP endpoint, if the peer can be reached directly
+    pub endpoint: Option<SocketAddr>,
+    /// peers it reaches directly, frames are not relayed to them
+    pub direct: HashSet<u32>,
+    /// sessions it hosts by session id, and when they were last published
+    pub sessions: HashMap<[u8; 16], (SessionInfo, Instant)>,
+    /// sequence numbers of its data frames
+    pub seq: SeqTracker,
+    /// signal strength of the frames it captures
+    pub signal: Signal,
+    /// header contexts of the frames sent to it and received from it
+    pub tx_contexts: Encoder,
+    pub rx_contexts: Decoder,
+}
+
+impl Peer {
+    /// Hello sent again from the same address, by a client that may have restarted and
+    /// started over its header contexts and sequence numbers
+    pub fn rejoin(&mut self) {
+        self.last_seen = Instant::now();
+        self.tx_contexts = Encoder::new(metrics::Component::Relay);
+        self.rx_contexts = Decoder::default();
+        self.seq = SeqTracker::default();
+    }
+    pub fn publish(&mut self, session: SessionInfo) {
+        if !self.sessions.contains_key(&session.session_id) {
+            log::info!(
+                "Peer {} in room {:?} hosts session {} of {:016x} on channel {}",
+                self.id,
+                self.room,
+                hex::encode(session.session_id),
+                session.local_communication_id,
+                session.channel
+            );
+        }
+        self.sessions
+            .insert(session.session_id, (session, Instant::now()));
+    }
+}
+
+/// Snapshot of a peer for the status API
+#[derive(Serialize, Debug)]
+pub struct PeerInfo {
+    pub id: u32,
+    pub addr: PeerAddr,
+    pub room: String,
+    pub role: Mode,
+    pub capabilities: u32,
+    pub software: String,
+    pub connected_secs: u64,
+    pub last_seen_secs_ago: f64,
+    pub stats: PeerStats,
+    pub seq: SeqStats,
+    pub signal_dbm: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct Rooms {
+    pub peers: HashMap<PeerAddr, Peer>,
+    pub rooms: HashMap<String, HashSet<PeerAddr>>,
+    macs: HashMap<String, MacTable>,
+    /// rooms whose peers changed since `take_changed`
+    changed: HashSet<String>,
+}
+
+impl Rooms {
+    pub fn join(&mut self, addr: PeerAddr, peer: Peer) {
+        self.leave(addr, "joined again");
+        log::info!(
+            "{} joined room {:?} as peer {} ({:?}, version {})",
+            addr,
+            peer.room,
+            peer.id,
+            peer.role,
+            peer.software
+        );
+        let room = self.rooms.entry(peer.room.clone()).or_default();
+        let peers = &self.peers;
+        let encryption = peer.capabilities & CAP_ENCRYPTION;
+        if let Some(other) = room
+            .iter()
+            .filter_map(|a| peers.get(a))
+            .find(|p| p.capabilities & CAP_ENCRYPTION != encryption)
+        {
+            log::warn!(
+                "Peer {} and {} disagree on encryption, they can't see each other",
+                peer.id,
+                other.id
+            );
+        }
+        room.insert(addr);
+        self.changed.insert(peer.room.clone());
+        self.peers.insert(addr, peer);
+        metrics::PEERS.set(self.peers.len() as i64);
+    }
+    pub fn leave(&mut self, addr: PeerAddr, reason: &str) -> Option<Peer> {
+        let peer = self.peers.remove(&addr)?;
+        metrics::PEERS.set(self.peers.len() as i64);
+        log::info!(
+            "{} left room {:?} as peer {}: {}",
+            addr,
+            peer.room,
+            peer.id,
+            reason
+        );
+        let seq = &peer.seq.stats;
+        if seq.received > 0 {
+            log::info!(
+                "Peer {} sent {} data frames, {} lost, {} reordered, {} duplicates",
+                peer.id,
+                seq.received,
+                seq.lost,
+                seq.reordered,
+                seq.duplicates
+            );
+        }
+        self.changed.insert(peer.room.clone());
+        if let Some(peers) = self.rooms.get_mut(&peer.room) {
+            peers.remove(&addr);
+            if peers.is_empty() {
+                self.rooms.remove(&peer.room);
+            }
+        }
+        if let Some(macs) = self.macs.get_mut(&peer.room) {
+            macs.forget(addr);
+            if macs.is_empty() {
+                self.macs.remove(&peer.room);
+            }
+        }
+        Some(peer)
+    }
+    /// Marks the peer alive, returns None if it is unknown
+    pub fn touch(&mut self, addr: PeerAddr) -> Option<&mut Peer> {
+        let peer = self.peers.get_mut(&addr)?;
+        peer.last_seen = Instant::now();
+        Some(peer)
+    }
+    /// Other peers in the same room as `addr`
+    pub fn neighbors(&self, addr: PeerAddr) -> Vec<PeerAddr> {
+        let room = match self.peers.get(&addr) {
+            Some(peer) => &self.rooms[&peer.room],
+            None => return vec![],
+        };
+        room.iter().filter(|a| **a != addr).cloned().collect()
+    }
+    pub fn take_changed(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.changed)
+    }
+    /// Peers an 802.11 frame from `addr` goes to, learning its transmitter address.
+    /// Unicast frames go to the peer behind the receiver address if it is known,
+    /// and to observers. Other frames are flooded to the room.
+    pub fn targets(&mut self, addr: PeerAddr, data: &[u8]) -> Vec<PeerAddr> {
+        let frame = match (
+            self.peers.get(&addr),
+            ieee80211::Frame::from_bytes((data, 0)),
+        ) {
+            (Some(peer), Ok((_, frame))) => {
+                let macs = self.macs.entry(peer.room.clone()).or_default();
+                if let Some(addr2) = frame.addr2.clone() {
+                    macs.learn(addr2, addr, Instant::now());
+                }
+                frame
+            }
+            _ => return self.neighbors(addr),
+        };
+        if frame.addr1.is_multicast() {
+            return self.neighbors(addr);
+        }
+        let room = &self.peers[&addr].room;
+        match self.macs.get(room).and_then(|m| m.lookup(&frame.addr1)) {
+            // the receiver sits behind the sender itself
+            Some(owner) if owner == addr => self.observers(addr),
+            Some(owner) => {
+                metrics::UNICAST.inc();
+                let mut targets = self.observers(addr);
+                targets.push(owner);
+                targets
+            }
+            None => self.neighbors(addr),
+        }
+    }
+    /// Observers in the same room as `addr`, they get every frame
+    fn observers(&self, addr: PeerAddr) -> Vec<PeerAddr> {
+        let mut observers = self.neighbors(addr);
+        observers.retain(|a| self.peers[a].role == Mode::Observer);
+        observers
+    }
+    /// Sessions of `room` or of all rooms, with the room and id of their host
+    pub fn sessions(&self, room: Option<&str>) -> Vec<(&str, u32, &SessionInfo)> {
+        let mut sessions: Vec<_> = self
+            .peers
+            .values()
+            .filter(|p| room.is_none() || room == Some(p.room.as_str()))
+            .flat_map(|p| {
+                p.sessions
+                    .values()
+                    .map(move |(s, _)| (&p.room[..], p.id, s))
+            })
+            .collect();
+        sessions.sort_by_key(|(room, id, s)| (*room, *id, s.session_id));
+        sessions
+    }
+    /// Role problems of a room, such as two hosts seeing the same Switch
+    pub fn mismatches(&self, room: &str, routing: &RoutingConfig) -> Vec<String> {
+        let mut peers: Vec<_> = self
+            .rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .map(|a| &self.peers[a])
+            .collect();
+        peers.sort_by_key(|p| p.id);
+        let ids = |role: Mode| -> Vec<u32> {
+            peers
+                .iter()
+                .filter(|p| p.role == role)
+                .map(|p| p.id)
+                .collect()
+        };
+        let (hosts, stations) = (ids(Mode::Host), ids(Mode::Station));
+        let mut mismatches = vec![];
+        if routing.routing == Routing::Roles {
+            if hosts.is_empty() && !stations.is_empty() {
+                mismatches.push(format!("stations {:?} have no host", stations));
+            }
+            if hosts.len() > 1 && routing.multi_host == MultiHost::Isolate {
+                mismatches.push(format!("hosts {:?} don't get each other's frames", hosts));
+            }
+        }
+        let mut switches = BTreeMap::<&ieee80211::Mac, Vec<u32>>::new();
+        for peer in &peers {
+            let macs: BTreeSet<_> = peer.sessions.values().map(|(s, _)| &s.host).collect();
+            for mac in macs {
+                switches.entry(mac).or_default().push(peer.id);
+            }
+        }
+        for (mac, ids) in switches {
+            if ids.len() > 1 {
+                mismatches.push(format!("hosts {:?} all claim Switch {:?}", ids, mac));
+            }
+        }
+        mismatches
+    }
+    pub fn find(&self, id: u32) -> Option<PeerAddr> {
+        self.peers
+            .iter()
+            .find(|(_, p)| p.id == id)
+            .map(|(addr, _)| *addr)
+    }
+    /// All peers coming from `ip`
+    pub fn peers_from(&self, ip: IpAddr) -> Vec<PeerAddr> {
+        self.peers
+            .keys()
+            .filter(|a| a.ip() == ip)
+            .cloned()
+            .collect()
+    }
+    /// Drops peers not seen for `timeout`
+    pub fn expire(&mut self, timeout: Duration) {
+        let expired: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.last_seen.elapsed() > timeout)
+            .map(|(a, _)| *a)
+            .collect();
+        for addr in expired {
+            self.leave(addr, "timed out");
+        }
+        let now = Instant::now();
+        for peer in self.peers.values_mut() {
+            peer.sessions
+                .retain(|_, (_, seen)| now.duration_since(*seen) <= 3 * SESSION_INTERVAL);
+        }
+        self.macs.retain(|_, macs| {
+            macs.expire(now);
+            !macs.is_empty()
+        });
+    }
+    pub fn info(&self, addr: PeerAddr) -> Option<PeerInfo> {
+        let peer = self.peers.get(&addr)?;
+        Some(PeerInfo {
+            id: peer.id,
+            addr,
+            room: peer.room.clone(),
+            role: peer.role,
+            capabilities: peer.capabilities,
+            software: peer.software.clone(),
+            connected_secs: peer.joined.elapsed().as_secs(),
+            last_seen_secs_ago: peer.last_seen.elapsed().as_secs_f64(),
+            stats: peer.stats.clone(),
+            seq: peer.seq.stats.clone(),
+            signal_dbm: peer.signal.dbm(),
+        })
+    }
+    pub fn room_info(&self, room: &str) -> Vec<PeerInfo> {
+        let mut peers: Vec<_> = self
+            .rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter_map(|a| self.info(*a))
+            .collect();
+        peers.sort_by_key(|p| p.id);
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::wlan_play::seq::Seq;
+
+    #[test]
+    fn test_rejoin() {
+        let mut peer = Peer {
+            id: 1,
+            room: "room".to_string(),
+            role: Mode::Station,
+            capabilities: 0,
+            software: String::new(),
+            joined: Instant::now(),
+            last_seen: Instant::now(),
+            stats: PeerStats::default(),
+            rate: RateLimit::new(&LimitsConfig::default()),
+            endpoint: None,
+            direct: HashSet::new(),
+            sessions: HashMap::new(),
+            seq: SeqTracker::default(),
+            signal: Signal::default(),
+            tx_contexts: Encoder::new(metrics::Component::Relay),
+            rx_contexts: Decoder::default(),
+        };
+        peer.seq.track(1000);
+        assert_eq!(peer.seq.track(1), Seq::Duplicate);
+        peer.rejoin();
+        // the restarted client counts from 1 again
+        assert_eq!(peer.seq.track(1), Seq::InOrder);
+        assert_eq!(peer.seq.track(2), Seq::InOrder);
+        assert_eq!(peer.seq.stats.lost, 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! Transports between clients and the server.
+//!
+//! UDP carries one frame per datagram. TCP prefixes each frame with its length
+//! as a little endian u16, WebSocket sends each frame as one binary message.
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Largest datagram or stream frame on any transport, larger frames are fragmented
+pub const MAX_FRAME: usize = u16::MAX as usize;
+/// Frames queued per stream connection before new ones are dropped
+const QUEUE: usize = 256;
+
+/// Server address in the client config: `host:port`, `udp://host:port`,
+/// `tcp://host:port` or `ws://host:port/path`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum ServerAddr {
+    Udp(String),
+    Tcp(String),
+    Ws(String),
+}
+
+impl FromStr for ServerAddr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let addr = match s.split_once("://") {
+            None => ServerAddr::Udp(s.to_string()),
+            Some(("udp", rest)) => ServerAddr::Udp(rest.to_string()),
+            Some(("tcp", rest)) => ServerAddr::Tcp(rest.to_string()),
+            Some(("ws", _)) => ServerAddr::Ws(s.to_string()),
+            // built without TLS, a reverse proxy can terminate it
+            Some(("wss", _)) => return Err(anyhow!("wss:// is not supported, use ws://")),
+            Some((scheme, _)) => return Err(anyhow!("Unknown transport {:?}", scheme)),
+        };
+        Ok(addr)
+    }
+}
+
+impl TryFrom<String> for ServerAddr {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Where a peer is connected from, peers are bridged across transports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    /// remote address and the index of the server socket it talks to
+    Udp(SocketAddr, usize),
+    Tcp(SocketAddr),
+    Ws(SocketAddr),
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            PeerAddr::Udp(a, _) | PeerAddr::Tcp(a) | PeerAddr::Ws(a) => a.ip(),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Udp(a, _) => write!(f, "udp://{}", a),
+            PeerAddr::Tcp(a) => write!(f, "tcp://{}", a),
+            PeerAddr::Ws(a) => write!(f, "ws://{}", a),
+        }
+    }
+}
+
+impl serde::Serialize for PeerAddr {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// Frames to send and received frames of a stream connection.
+/// The connection is closed when the sender is dropped.
+pub type Channels = (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>);
+
+/// Runs a length prefixed stream in background tasks
+pub fn tcp_channels<S>(stream: S) -> Channels
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut r, mut w) = tokio::io::split(stream);
+    let (in_tx, in_rx) = mpsc::channel(QUEUE);
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(QUEUE);
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_FRAME];
+        while let Ok(len) = r.read_u16_le().await {
+            let len = len as usize;
+            if len > MAX_FRAME {
+                log::warn!("Stream frame of {} bytes is too large", len);
+                break;
+            }
+            if r.read_exact(&mut buf[..len]).await.is_err()
+                || in_tx.send(buf[..len].to_vec()).await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            let mut bytes = (frame.len() as u16).to_le_bytes().to_vec();
+            bytes.extend_from_slice(&frame);
+            if w.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+        let _ = w.shutdown().await;
+    });
+    (out_tx, in_rx)
+}
+
+/// WebSocket limits, messages are single frames
+pub fn ws_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_FRAME),
+        max_frame_size: Some(MAX_FRAME),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// Runs a WebSocket in background tasks
+pub fn ws_channels<S>(ws: WebSocketStream<S>) -> Channels
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut w, mut r) = ws.split();
+    let (in_tx, in_rx) = mpsc::channel(QUEUE);
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(QUEUE);
+    tokio::spawn(async move {
+        while let Some(Ok(msg)) = r.next().await {
+            let frame = match msg {
+                Message::Binary(frame) => frame,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if in_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            if w.send(Message::Binary(frame)).await.is_err() {
+                break;
+            }
+        }
+        let _ = w.close().await;
+    });
+    (out_tx, in_rx)
+}
+
+/// Client side of a transport
+pub enum Transport {
+    /// not connected, so peers can reach it directly
+    Udp {
+        socket: UdpSocket,
+        server: SocketAddr,
+        buf: Mutex<Vec<u8>>,
+    },
+    Stream {
+        tx: mpsc::Sender<Vec<u8>>,
+        rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+    },
+}
+
+impl Transport {
+    pub async fn connect(addr: &ServerAddr) -> Result<Transport> {
+        let (tx, rx) = match addr {
+            ServerAddr::Udp(addr) => {
+                let addr = tokio::net::lookup_host(addr.as_str())
+                    .await?
+                    .next()
+                    .ok_or_else(|| anyhow!("Can't resolve {}", addr))?;
+                let local = if addr.is_ipv6() {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                };
+                let socket = UdpSocket::bind(local).await?;
+                return Ok(Transport::Udp {
+                    socket,
+                    server: addr,
+                    buf: Mutex::new(vec![0; MAX_FRAME]),
+                });
+            }
+            ServerAddr::Tcp(addr) => {
+                let s = TcpStream::connect(addr.as_str()).await?;
+                s.set_nodelay(true)?;
+                tcp_channels(s)
+            }
+            ServerAddr::Ws(url) => {
+                let (ws, _) = tokio_tungstenite::connect_async_with_config(
+                    url.as_str(),
+                    Some(ws_config()),
+                    true,
+                )
+                .await?;
+                ws_channels(ws)
+            }
+        };
+        Ok(Transport::Stream {
+            tx,
+            rx: Mutex::new(rx),
+        })
+    }
+    pub fn is_udp(&self) -> bool {
+        matches!(self, Transport::Udp { .. })
+    }
+    /// Sends a frame to the server
+    pub async fn send(&self, frame: &[u8]) -> Result<()> {
+        match self {
+            Transport::Udp { socket, server, .. } => {
+                socket.send_to(frame, server).await?;
+            }
+            Transport::Stream { tx, .. } => tx
+                .send(frame.to_vec())
+                .await
+                .map_err(|_| anyhow!("Connection to server closed"))?,
+        }
+        Ok(())
+    }
+    /// Sends a frame directly to a peer
+    pub async fn send_to(&self, frame: &[u8], addr: SocketAddr) -> Result<()> {
+        match self {
+            Transport::Udp { socket, .. } => {
+                socket.send_to(frame, addr).await?;
+                Ok(())
+            }
+            Transport::Stream { .. } => Err(anyhow!("Peers can only be reached over UDP")),
+        }
+    }
+    /// Receives the next frame and where it comes from, None if it is the
+    /// server. Cancel safe.
+    pub async fn recv(&self) -> Result<(Vec<u8>, Option<SocketAddr>)> {
+        match self {
+            Transport::Udp {
+                socket,
+                server,
+                buf,
+            } => {
+                let mut buf = buf.lock().await;
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                Ok((buf[..len].to_vec(), Some(from).filter(|f| f != server)))
+            }
+            Transport::Stream { rx, .. } => match rx.lock().await.recv().await {
+                Some(frame) => Ok((frame, None)),
+                None => Err(anyhow!("Connection to server closed")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_addr() {
+        let parse = |s: &str| s.parse::<ServerAddr>().unwrap();
+        assert_eq!(parse("1.2.3.4:5"), ServerAddr::Udp("1.2.3.4:5".into()));
+        assert_eq!(parse("udp://h:5"), ServerAddr::Udp("h:5".into()));
+        assert_eq!(parse("tcp://h:5"), ServerAddr::Tcp("h:5".into()));
+        assert_eq!(parse("ws://h:5/x"), ServerAddr::Ws("ws://h:5/x".into()));
+        assert!("quic://h:5".parse::<ServerAddr>().is_err());
+        assert!("wss://h:5/x".parse::<ServerAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_framing() {
+        let (a, b) = tokio::io::duplex(64);
+        let (a_tx, _a_rx) = tcp_channels(a);
+        let (_b_tx, mut b_rx) = tcp_channels(b);
+        a_tx.send(vec![1, 2, 3]).await.unwrap();
+        a_tx.send(Vec::new()).await.unwrap();
+        a_tx.send(vec![4; 300]).await.unwrap();
+        assert_eq!(b_rx.recv().await.unwrap(), vec![1, 2, 3]);
+        assert!(b_rx.recv().await.unwrap().is_empty());
+        assert_eq!(b_rx.recv().await.unwrap(), vec![4; 300]);
+    }
+}